@@ -1,9 +0,0 @@
-#[allow(dead_code)]
-pub const PROD_ADMINS: &'static [&'static str] = &[
-    // Add principles of controllers and admins here
-   ];
-
-#[allow(dead_code)]
-pub const DEV_ADMINS: &'static [&'static str] = &[
-    // Add principles of controllers and admins here
-    ];
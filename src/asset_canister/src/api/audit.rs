@@ -0,0 +1,11 @@
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+use crate::database::audit::AuditEntry;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AuditLogPage {
+    // Newest first
+    pub entries: Vec<AuditEntry>,
+    // Pass back as `before` to get the next page, None once there are no more entries
+    pub next_before: Option<u64>,
+}
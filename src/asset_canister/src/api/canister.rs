@@ -1,8 +1,20 @@
+use candid::Principal;
 use ic_cdk::export::candid::{CandidType, Deserialize};
 
+use crate::models::config::Config;
+
 #[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub struct CanisterInfo {
     pub heap_memory_size: u64,
     pub memory_size: u64,
     pub cycles: u64,
 }
+
+/// Arguments for installing or upgrading the canister
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InitArgs {
+    // Added to the admin list alongside the controllers
+    pub admins: Vec<Principal>,
+    // Replaces the saved config, which is kept if this is None
+    pub config: Option<Config>,
+}
@@ -0,0 +1,46 @@
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+/// The error returned by every endpoint
+/// `retry_after` is in nanoseconds from the time of the call
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum AssetError {
+    // auth
+    Anonymous,
+    Unauthorized,
+    Blocked,
+    NotOwner,
+    CannotRevokeOwner,
+    RateLimited { retry_after: u64 },
+    DailyUploadLimit { retry_after: u64 },
+    QuotaExceeded { limit: u64, used: u64 },
+    CanisterFull,
+    // files and chunks
+    FileNotFound,
+    FileDeleted,
+    NotInTrash,
+    VersionNotFound,
+    ChunkNotFound,
+    UserNotFound,
+    InvalidFileType,
+    FileTooLarge { max_chunks: u64 },
+    ChunkTooLarge { max_bytes: u64 },
+    ChunkTooSmall { chunk_size: u64 },
+    ChunkOutOfRange,
+    ChunkAlreadyUploaded,
+    UploadComplete,
+    MissingChunks,
+    HashMismatch,
+    InvalidHash,
+    InvalidCursor,
+    InvalidSearchQuery,
+    InvalidMetadata(String),
+    // moderation
+    NotBlocked,
+    AppealPending,
+    AppealNotFound,
+    AppealTooLong { max_length: u64 },
+    // config
+    InvalidConfig(String),
+    // the stable structures refused a write
+    StorageError(String),
+}
@@ -1,7 +1,10 @@
 use candid::Principal;
 use ic_cdk::export::candid::{CandidType, Deserialize};
+use serde_bytes::ByteBuf;
 
 use crate::database::{chunks::ChunkID, file::FileID};
+use crate::models::file::{FileType, Visibility};
+use crate::models::metadata::FileMetadata;
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct FEFile {
@@ -11,8 +14,45 @@ pub struct FEFile {
     pub file_name: String,
     pub file_type: String,
     pub owner: Principal,
-    pub metadata: String,
+    pub metadata: FileMetadata,
     pub url: String,
     pub created_at: u64,
     pub updated_at: u64,
+    pub visibility: Visibility,
+    // Set while the file is in the trash
+    pub deleted_at: Option<u64>,
+    pub version: u64,
+    // Set on the upload of new content for an existing file, which takes its place once committed
+    pub replaces: Option<FileID>,
+}
+
+/// One version of a file's content, the chunks can be fetched with `get_chunk_by_id`
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FEFileVersion {
+    pub version: u64,
+    pub file_type: String,
+    pub chunk_ids: Vec<ChunkID>,
+    pub number_of_chunks: u64,
+    pub size: u64,
+    // Serves this version over HTTP
+    pub url: String,
+    // None for the current version
+    pub replaced_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FilePage {
+    pub files: Vec<FEFile>,
+    // Pass back to get the next page, None once there are no more files
+    pub next_cursor: Option<ByteBuf>,
+}
+
+/// Filters for listing files, unset fields match every file
+/// `created_after` and `created_before` are inclusive nanosecond timestamps
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileFilter {
+    pub file_type: Option<FileType>,
+    pub owner: Option<Principal>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
 }
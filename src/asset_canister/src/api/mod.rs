@@ -1,2 +1,5 @@
+pub mod audit;
 pub mod canister;
+pub mod error;
 pub mod file;
+pub mod user;
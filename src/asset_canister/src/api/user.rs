@@ -0,0 +1,35 @@
+use candid::Principal;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+use crate::models::role::Role;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UserUsage {
+    pub principal: Principal,
+    pub bytes_used: u64,
+    pub byte_limit: u64,
+    // Whether the limit is the default quota rather than one set for this user
+    pub default_tier: bool,
+    pub number_of_files: u64,
+}
+
+/// What a user can see about their own account
+/// Times are in nanoseconds since the epoch
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Account {
+    pub principal: Principal,
+    pub blocked: bool,
+    pub bytes_used: u64,
+    pub byte_limit: u64,
+    pub number_of_files: u64,
+    pub uploads_remaining_today: u64,
+    // When another upload is allowed, None if none were made in the last day
+    pub next_upload_reset: Option<u64>,
+    pub rate_limited_until: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RoleAssignment {
+    pub principal: Principal,
+    pub role: Role,
+}
@@ -0,0 +1,162 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    api::error::AssetError,
+    database::memory::{get_memory, Memory, APPEALS_MEMORY_ID},
+};
+
+use super::{
+    moderation::{is_blocked, unblock_user},
+    warnings::reset_warnings,
+};
+
+// Keys are principals, which are at most 29 bytes
+const MAX_KEY_SIZE: u32 = 29;
+// Room for the longest appeal, 1000 characters of up to 4 bytes each, once resolved
+const MAX_VALUE_SIZE: u32 = 4400;
+
+/// Appeals are at most 1000 characters
+pub const MAX_APPEAL_LENGTH: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum AppealStatus {
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// A blocked user's request to be unblocked, each user has at most one
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Appeal {
+    pub principal: Principal,
+    pub text: String,
+    pub status: AppealStatus,
+    pub submitted_at: u64,
+    pub resolved_at: Option<u64>,
+    pub resolved_by: Option<Principal>,
+}
+
+impl Storable for Appeal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+thread_local! {
+    static APPEALS: RefCell<StableBTreeMap<Memory, Vec<u8>, Appeal>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(APPEALS_MEMORY_ID),
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+    );
+}
+
+/// Replaces the user's last appeal, unless it is still pending
+pub fn submit_appeal(principal: Principal, text: String) -> Result<Appeal, AssetError> {
+    if !is_blocked(principal) {
+        return Err(AssetError::NotBlocked);
+    }
+    if text.chars().count() as u64 > MAX_APPEAL_LENGTH {
+        return Err(AssetError::AppealTooLong {
+            max_length: MAX_APPEAL_LENGTH,
+        });
+    }
+
+    match get_appeal(principal) {
+        Some(appeal) if appeal.status == AppealStatus::Pending => Err(AssetError::AppealPending),
+        _ => insert_appeal(Appeal {
+            principal,
+            text,
+            status: AppealStatus::Pending,
+            submitted_at: time(),
+            resolved_at: None,
+            resolved_by: None,
+        }),
+    }
+}
+
+/// Accepting an appeal unblocks the user and clears their warnings
+pub fn resolve_appeal(
+    principal: Principal,
+    accept: bool,
+    resolved_by: Principal,
+) -> Result<Appeal, AssetError> {
+    match get_appeal(principal) {
+        Some(appeal) if appeal.status == AppealStatus::Pending => {
+            // The user is only unblocked once the appeal is saved as accepted
+            let resolved_appeal = insert_appeal(Appeal {
+                status: match accept {
+                    true => AppealStatus::Accepted,
+                    false => AppealStatus::Rejected,
+                },
+                resolved_at: Some(time()),
+                resolved_by: Some(resolved_by),
+                ..appeal
+            })?;
+
+            if accept {
+                unblock_user(principal);
+                reset_warnings(principal);
+            }
+            Ok(resolved_appeal)
+        }
+        _ => Err(AssetError::AppealNotFound),
+    }
+}
+
+/// Lists appeals with the given status, or every appeal
+pub fn get_appeals(status: Option<AppealStatus>) -> Vec<Appeal> {
+    APPEALS.with(|appeals| {
+        appeals
+            .borrow()
+            .iter()
+            .map(|(_, appeal)| appeal)
+            .filter(|appeal| status.is_none_or(|status| appeal.status == status))
+            .collect()
+    })
+}
+
+pub fn get_appeal(principal: Principal) -> Option<Appeal> {
+    APPEALS.with(|appeals| appeals.borrow().get(&principal.as_slice().to_vec()))
+}
+
+fn insert_appeal(appeal: Appeal) -> Result<Appeal, AssetError> {
+    match APPEALS.with(|appeals| {
+        appeals
+            .borrow_mut()
+            .insert(appeal.principal.as_slice().to_vec(), appeal.clone())
+    }) {
+        Ok(_) => Ok(appeal),
+        Err(e) => Err(AssetError::StorageError(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_resolved_appeal_can_be_stored() {
+        let principal = Principal::from_slice(&[1; 29]);
+        let appeal = Appeal {
+            principal,
+            text: "\u{10FFFF}".repeat(MAX_APPEAL_LENGTH as usize),
+            status: AppealStatus::Accepted,
+            submitted_at: u64::MAX,
+            resolved_at: Some(u64::MAX),
+            resolved_by: Some(principal),
+        };
+        assert!(appeal.to_bytes().len() <= MAX_VALUE_SIZE as usize);
+
+        insert_appeal(appeal.clone()).unwrap();
+        assert_eq!(get_appeal(principal), Some(appeal));
+    }
+}
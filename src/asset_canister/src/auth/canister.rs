@@ -1,6 +1,7 @@
+use crate::api::error::AssetError;
 use crate::metrics::metrics::get_stable_memory_size;
 
-use super::file::MAX_FILE_SIZE;
+use crate::database::config::get_config;
 
 /// Max canister size is 16GB
 /// Stable storage is being upgraded to 32GB but I'm not sure when this is
@@ -11,9 +12,9 @@ const MAX_SIZE: u64 = 4000000000 * 4;
 const SAFETY_BUFFER: u64 = 2000000;
 
 /// Checks the canister has space to store a file of the maximum size
-pub fn canister_storage_ok() -> Result<u64, String> {
-    match get_stable_memory_size() + MAX_FILE_SIZE - SAFETY_BUFFER < MAX_SIZE {
+pub fn canister_storage_ok() -> Result<u64, AssetError> {
+    match get_stable_memory_size() + get_config().max_file_size - SAFETY_BUFFER < MAX_SIZE {
         true => Ok(get_stable_memory_size()),
-        false => Err(String::from("Canister is full")),
+        false => Err(AssetError::CanisterFull),
     }
 }
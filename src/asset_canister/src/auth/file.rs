@@ -4,58 +4,87 @@ use candid::Principal;
 use ic_cdk::caller;
 
 use crate::{
+    api::error::AssetError,
     database::{
-        chunks::get_all_chunks_for_file as be_get_all_chunks_for_file,
-        file::{get_file_by_id, FileID},
+        config::get_config,
+        file::{get_file_by_id, reserved_bytes, FileID},
+        users::get_usage,
+    },
+    models::{
+        file::{File, Visibility},
+        metadata::FileMetadata,
     },
-    models::file::File,
 };
 
 use super::{
     canister::canister_storage_ok,
     moderation::is_blocked,
     ratelimit::{rate_limit, RateLimitMessageType},
-    user::{get_logged_in_principal, get_logged_in_superuser},
+    roles::{has_permission, Permission},
+    user::{get_logged_in_principal, get_logged_in_with_permission},
 };
 
-/// Max file size is currently 11.4MB (total of 6 chunks * 1.9MB chunk size)
+/// Max file size defaults to 11.4MB (total of 6 chunks * 1.9MB chunk size)
 pub const MAX_FILE_SIZE: u64 = 11400000;
 
-/// We allow a max file size of 11.4MB, which is 6 chunks
+/// We allow a max file size of 11.4MB by default, which is 6 chunks
 pub const MAX_CHUNKS: u64 = 6;
 
+// Limits on file names and metadata, in characters
+const MAX_FILE_NAME_LENGTH: usize = 255;
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_ALT_TEXT_LENGTH: usize = 1000;
+const MAX_DESCRIPTION_LENGTH: usize = 5000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LENGTH: usize = 50;
+const MAX_CUSTOM_FIELDS: usize = 20;
+const MAX_CUSTOM_KEY_LENGTH: usize = 50;
+const MAX_CUSTOM_VALUE_LENGTH: usize = 1000;
+
 /// Chunk size is set just below the 2MB message limit at 1.9MB
+/// The config can lower it, and files uploaded before there was a config use it
 pub const CHUNK_SIZE: u64 = 1900000;
 
 /// Checks the proposed number of chunks is under the allowed amount
-pub fn file_size_accepted(number_of_chunks: u64) -> Result<Principal, String> {
-    match get_logged_in_superuser() {
-        // let admins save larger files
+#[allow(clippy::needless_return)]
+pub fn file_size_accepted(number_of_chunks: u64) -> Result<Principal, AssetError> {
+    match get_logged_in_with_permission(Permission::BypassLimits) {
+        // let trusted uploaders save larger files
         Ok(principal) => Ok(principal),
         Err(_) => {
-            if number_of_chunks <= MAX_CHUNKS {
+            let max_chunks = get_config().max_chunks_per_file();
+            if number_of_chunks <= max_chunks {
                 return Ok(caller());
             } else {
-                return Err(String::from("Number of chunks exceeds file size"));
+                return Err(AssetError::FileTooLarge { max_chunks });
+            }
+        }
+    }
+}
+
+/// Checks the user has room for the largest the file could be
+/// Trusted uploaders can upload past their quota
+pub fn quota_accepted(principal: Principal, number_of_chunks: u64) -> Result<(), AssetError> {
+    match get_logged_in_with_permission(Permission::BypassLimits) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            let (used, limit) = get_usage(principal);
+            let reserved = reserved_bytes(number_of_chunks, get_config().chunk_size);
+            match used.saturating_add(reserved) <= limit {
+                true => Ok(()),
+                false => Err(AssetError::QuotaExceeded { limit, used }),
             }
         }
     }
 }
 
 /// Checks the caller owns the file
-pub fn caller_owns_file_or_is_superuser(file_id: FileID) -> Result<File, String> {
+pub fn caller_owns_file_or_is_superuser(file_id: FileID) -> Result<File, AssetError> {
     match get_logged_in_principal() {
         Ok(_) => match get_file_by_id(&file_id) {
-            // let admins access files
-            Ok(file) => match get_logged_in_superuser() {
+            Ok(file) => match caller_can_manage_file(&file) {
                 Ok(_) => Ok(file),
-                Err(_) => {
-                    if file.owner == caller() {
-                        return Ok(file);
-                    } else {
-                        return Err(String::from("Caller is not the owner of the file"));
-                    }
-                }
+                Err(e) => Err(e),
             },
             Err(e) => Err(e),
         },
@@ -63,13 +92,47 @@ pub fn caller_owns_file_or_is_superuser(file_id: FileID) -> Result<File, String>
     }
 }
 
+/// Checks the caller owns the file or can manage every file
+pub fn caller_can_manage_file(file: &File) -> Result<(), AssetError> {
+    // let file managers access files
+    match get_logged_in_with_permission(Permission::ManageFiles) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            if file.owner == caller() {
+                Ok(())
+            } else {
+                Err(AssetError::NotOwner)
+            }
+        }
+    }
+}
+
+/// Checks the caller can fetch the file, which anyone can unless it is private
+pub fn caller_can_read_file(file: &File) -> Result<(), AssetError> {
+    let caller = caller();
+    match file.can_be_read_by(caller) || has_permission(caller, Permission::ReadPrivateFiles) {
+        true => Ok(()),
+        false => Err(AssetError::Unauthorized),
+    }
+}
+
+/// Whether the caller should see the file in search results
+/// Unlisted and private files are only found by those they were shared with, owners and admins
+pub fn caller_can_find_file(file: &File) -> bool {
+    let caller = caller();
+    file.get_visibility() == Visibility::Public
+        || caller == file.owner
+        || file.accessors.contains(&caller)
+        || has_permission(caller, Permission::ReadPrivateFiles)
+}
+
 /// Checks that the caller
 /// Is authenticated
 /// Is not rate limited
 /// Is not blocked
 /// Has not created too many files
 /// That the canister has space to accept the file
-pub fn caller_accepted(call_type: RateLimitMessageType) -> Result<Principal, String> {
+pub fn caller_accepted(call_type: RateLimitMessageType) -> Result<Principal, AssetError> {
     match get_logged_in_principal() {
         Ok(principal) => match rate_limit(principal, call_type) {
             Ok(_) => match is_blocked(principal) {
@@ -77,7 +140,7 @@ pub fn caller_accepted(call_type: RateLimitMessageType) -> Result<Principal, Str
                     Ok(_) => Ok(principal),
                     Err(e) => Err(e),
                 },
-                true => Err(String::from("Unauthorized")),
+                true => Err(AssetError::Blocked),
             },
             Err(e) => Err(e),
         },
@@ -85,25 +148,69 @@ pub fn caller_accepted(call_type: RateLimitMessageType) -> Result<Principal, Str
     }
 }
 
-/// Check the number of chunks *already* saved with this FileID isn't reached
-pub fn chunks_within_file_size(file: &File) -> Result<String, String> {
-    let number_of_chunks = file.number_of_chunks.clone();
-    match be_get_all_chunks_for_file(&file) {
-        Ok(chunks) => match chunks.len() <= number_of_chunks as usize {
-            true => Ok(String::from("Chunks are within filesize")),
-            false => Err(String::from("All chunks already allocated for file")),
-        },
-        Err(e) => Err(e),
+/// Checks a new file name and metadata fit within the limits, which are in characters
+pub fn metadata_accepted(
+    file_name: &Option<String>,
+    metadata: &FileMetadata,
+) -> Result<(), AssetError> {
+    let too_long = |value: &str, max_length: usize| value.chars().count() > max_length;
+    let invalid = |message: &str| Err(AssetError::InvalidMetadata(String::from(message)));
+
+    if let Some(file_name) = file_name {
+        if file_name.trim().is_empty() || too_long(file_name, MAX_FILE_NAME_LENGTH) {
+            return invalid("file_name must be between 1 and 255 characters");
+        }
+    }
+    if metadata
+        .title
+        .as_ref()
+        .is_some_and(|title| too_long(title, MAX_TITLE_LENGTH))
+    {
+        return invalid("title must be at most 200 characters");
+    }
+    if metadata
+        .alt_text
+        .as_ref()
+        .is_some_and(|alt_text| too_long(alt_text, MAX_ALT_TEXT_LENGTH))
+    {
+        return invalid("alt_text must be at most 1000 characters");
+    }
+    if metadata
+        .description
+        .as_ref()
+        .is_some_and(|description| too_long(description, MAX_DESCRIPTION_LENGTH))
+    {
+        return invalid("description must be at most 5000 characters");
     }
+    if metadata.tags.len() > MAX_TAGS
+        || metadata
+            .tags
+            .iter()
+            .any(|tag| too_long(tag, MAX_TAG_LENGTH))
+    {
+        return invalid("at most 20 tags of at most 50 characters each");
+    }
+    if metadata.custom.len() > MAX_CUSTOM_FIELDS
+        || metadata.custom.iter().any(|(key, value)| {
+            too_long(key, MAX_CUSTOM_KEY_LENGTH) || too_long(value, MAX_CUSTOM_VALUE_LENGTH)
+        })
+    {
+        return invalid("at most 20 custom fields, with keys of at most 50 characters and values of at most 1000");
+    }
+
+    Ok(())
 }
 
 /// Check the number of bytes within a chunk is acceptable
 /// This is actually enforced by canister message size limitation
 /// But let's re-enforce here just to be sure
-pub fn chunk_size_okay(number_of_bytes: usize) -> Result<Principal, String> {
-    if number_of_bytes <= CHUNK_SIZE as usize {
+#[allow(clippy::needless_return)]
+pub fn chunk_size_okay(number_of_bytes: usize, chunk_size: u64) -> Result<Principal, AssetError> {
+    if number_of_bytes <= chunk_size as usize {
         return Ok(caller());
     } else {
-        return Err(String::from("Too many bytes in chunk"));
+        return Err(AssetError::ChunkTooLarge {
+            max_bytes: chunk_size,
+        });
     }
 }
@@ -1,5 +1,9 @@
+pub mod appeals;
 pub mod canister;
 pub mod file;
 pub mod moderation;
 pub mod ratelimit;
+pub mod roles;
+pub mod share_link;
 pub mod user;
+pub mod warnings;
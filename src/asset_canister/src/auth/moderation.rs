@@ -1,10 +1,15 @@
-use candid::CandidType;
+use candid::{CandidType, Decode, Encode};
 use ic_cdk::export::Principal;
+use ic_stable_structures::{StableBTreeMap, Storable};
 use serde::{Deserialize as SerdeDe, Serialize};
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::{borrow::Cow, cell::RefCell};
 
-use crate::database::file::delete_all_files_by_prinicipal;
+use crate::database::memory::{get_memory, Memory, BLOCKED_MEMORY_ID};
+use crate::jobs::deletion::start_deletion_job;
+
+// Keys are principals, which are at most 29 bytes
+const MAX_KEY_SIZE: u32 = 29;
+const MAX_VALUE_SIZE: u32 = 1024;
 
 // TODO: move this into a library to avoid code duplication between packages
 
@@ -14,51 +19,72 @@ pub struct Blocked {
     pub metadata: String,
 }
 
-pub type BlockedStore = HashMap<Principal, Blocked>;
+impl Storable for Blocked {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
 
 thread_local! {
-    pub static BLOCKED_STORE: RefCell<BlockedStore> = RefCell::default();
+    static BLOCKED_STORE: RefCell<StableBTreeMap<Memory, Vec<u8>, Blocked>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(BLOCKED_MEMORY_ID),
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+    );
 }
 
 pub fn is_blocked(principal: Principal) -> bool {
-    match BLOCKED_STORE.with(|blocked_store| blocked_store.borrow().get(&principal).cloned()) {
-        Some(_) => true,
-        None => false,
-    }
+    BLOCKED_STORE.with(|blocked_store| {
+        blocked_store
+            .borrow()
+            .contains_key(&principal.as_slice().to_vec())
+    })
 }
 
 pub fn get_blocked_users() -> Vec<Principal> {
     let mut blocked_users: Vec<Principal> = vec![];
 
     BLOCKED_STORE.with(|blocked_store| {
-        blocked_store
-            .borrow()
-            .iter()
-            .for_each(|(principal, _warning)| {
-                blocked_users.push(*principal);
-            })
+        blocked_store.borrow().iter().for_each(|(_key, blocked)| {
+            blocked_users.push(blocked.principal);
+        })
     });
 
     blocked_users
 }
 
-pub fn block_and_delete_user(principal: Principal) {
-    block_user(principal);
-    delete_all_files_by_prinicipal(principal);
+/// Blocks the user and queues the deletion of all of their files, which happens in batches
+pub fn block_and_delete_user(principal: Principal, reason: String) {
+    block_user(principal, reason);
+    start_deletion_job(principal);
 }
 
-pub fn block_user(principal: Principal) {
-    BLOCKED_STORE.with(|block_store| {
-        block_store.borrow_mut().insert(
-            principal,
-            Blocked {
-                principal,
-                metadata: String::from(""),
-            },
-        )
+/// The reason is kept as the block's metadata
+pub fn block_user(principal: Principal, reason: String) {
+    insert_blocked(Blocked {
+        principal,
+        metadata: reason,
+    });
+}
+
+pub fn insert_blocked(blocked: Blocked) {
+    let _ = BLOCKED_STORE.with(|block_store| {
+        block_store
+            .borrow_mut()
+            .insert(blocked.principal.as_slice().to_vec(), blocked)
     });
 }
 
 pub fn unblock_user(principal: Principal) {
-    BLOCKED_STORE.with(|block_store| block_store.borrow_mut().remove(&principal));
+    BLOCKED_STORE.with(|block_store| {
+        block_store
+            .borrow_mut()
+            .remove(&principal.as_slice().to_vec())
+    });
 }
@@ -1,30 +1,66 @@
 use ic_cdk::api::time;
 use std::{cell::RefCell, collections::HashMap};
 
-use candid::{CandidType, Deserialize, Principal};
+use candid::Principal;
 
-use super::{moderation::block_user, user::get_logged_in_superuser};
+use crate::api::error::AssetError;
+use crate::database::config::get_config;
 
-pub type RateLimit = HashMap<Principal, Vec<Call>>;
+use super::{
+    moderation::block_user,
+    roles::Permission,
+    user::get_logged_in_with_permission,
+    warnings::{get_number_of_warnings, insert_warning},
+};
 
+/// The time at which each bucket will be full again, in nano seconds
+/// A bucket that is already full holds no state, so it is left out of the map
+pub type RateLimit = HashMap<(Principal, Bucket), u64>;
+
+/// Allows `limit` calls in any `period` (in nano seconds), refilling one call every `period / limit`
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub struct Call {
-    time: u64,
-    call_type: RateLimitMessageType,
-    principal: Principal,
+pub struct RateLimitConfig {
+    pub limit: u64,
+    pub period: u64,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
-pub struct Warning {
-    number: i128,
-    principal: Principal,
+impl RateLimitConfig {
+    fn refill_interval(&self) -> u64 {
+        self.period / self.limit
+    }
+
+    /// How long until a call can be taken from a bucket that is `time_until_full` from full,
+    /// None if one can be taken now
+    /// The bucket is only empty once it would take longer than a whole period to refill it
+    fn retry_after(&self, time_until_full: u64) -> Option<u64> {
+        let capacity = self.period - self.refill_interval();
+        match time_until_full > capacity {
+            true => Some(time_until_full - capacity),
+            false => None,
+        }
+    }
+
+    /// How long until the bucket is full once a call is taken from it
+    fn time_until_full_after_take(&self, time_until_full: u64) -> u64 {
+        time_until_full + self.refill_interval()
+    }
+
+    /// How long until the bucket is full once a call is given back to it
+    fn time_until_full_after_give_back(&self, time_until_full: u64) -> u64 {
+        time_until_full.saturating_sub(self.refill_interval())
+    }
 }
 
-pub type Warnings = HashMap<Principal, Warning>;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Bucket {
+    // Every call counts against this
+    Calls,
+    // File creations, which a deletion gives back
+    Uploads,
+}
 
 thread_local! {
     pub static RATE_LIMIT_STORE: RefCell<RateLimit> = RefCell::default();
-    pub static WARNED_USERS: RefCell<Warnings> = RefCell::default();
 }
 
 /// One day in nano seconds
@@ -35,8 +71,15 @@ pub const FILES_PER_DAY: u64 = 3;
 pub const RATE_LIMIT: u64 = 300000000000;
 // How many calls you're allowed to make in that window
 pub const CALL_RATE_LIMIT_WINDOW: u64 = 50;
+// Principals are blocked once they pass this many warnings
+pub const WARNINGS_BEFORE_BLOCK: u64 = 200;
+// Warnings decay after a week by default
+pub const WARNING_DECAY: u64 = 7 * FILES_REFRESH_RATE_ONE_DAY;
+
+const RATE_LIMIT_WARNING_REASON: &str = "Exceeded the call rate limit";
+const WARNING_LIMIT_BLOCK_REASON: &str = "Passed the warning limit";
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RateLimitMessageType {
     CreateFile,
@@ -45,166 +88,172 @@ pub enum RateLimitMessageType {
     GetFile,
 }
 
-// TODO: add admin way to clear the rate_limit cache or do this in a heartbeat script
-
 pub fn rate_limit(
     principal: Principal,
     message_type: RateLimitMessageType,
-) -> Result<String, String> {
-    let new_call = Call {
-        time: time(),
-        call_type: message_type,
-        principal,
-    };
-    match get_logged_in_superuser() {
-        Ok(_) => Ok(String::from("Superuser")),
-        Err(_) => {
-            let calls = get_calls(principal);
-            match check_rate_limit(calls.clone()) {
-                Ok(res) => {
-                    if message_type == RateLimitMessageType::DeleteFile {
-                        remove_last_create_call(principal, calls.clone());
-                        insert_call(principal, new_call);
-                        Ok(res)
-                    } else if message_type == RateLimitMessageType::CreateFile {
-                        match check_last_three_signals(calls.clone()) {
-                            Ok(message) => {
-                                insert_call(principal, new_call);
-                                Ok(message)
-                            }
-                            Err(e) => Err(e),
+) -> Result<(), AssetError> {
+    match get_logged_in_with_permission(Permission::BypassLimits) {
+        Ok(_) => Ok(()),
+        Err(_) => match check_bucket(principal, Bucket::Calls) {
+            Ok(_) => match message_type {
+                RateLimitMessageType::CreateFile => {
+                    match check_bucket(principal, Bucket::Uploads) {
+                        Ok(_) => {
+                            take_from_bucket(principal, Bucket::Calls);
+                            take_from_bucket(principal, Bucket::Uploads);
+                            Ok(())
                         }
-                    } else {
-                        insert_call(principal, new_call);
-                        Ok(res)
+                        Err(e) => Err(e),
                     }
                 }
-                Err(e) => {
-                    if get_number_of_warnings(principal) > 200 {
-                        insert_call(principal, new_call);
-                        block_user(principal);
-                        Err(e)
-                    } else {
-                        insert_call(principal, new_call);
-                        insert_warning(principal);
-                        Err(e)
+                RateLimitMessageType::DeleteFile => {
+                    take_from_bucket(principal, Bucket::Calls);
+                    give_back_to_bucket(principal, Bucket::Uploads);
+                    Ok(())
+                }
+                _ => {
+                    take_from_bucket(principal, Bucket::Calls);
+                    Ok(())
+                }
+            },
+            Err(e) => {
+                if get_number_of_warnings(principal) > get_config().warnings_before_block {
+                    block_user(principal, String::from(WARNING_LIMIT_BLOCK_REASON));
+                } else {
+                    if let Err(e) = insert_warning(principal, RATE_LIMIT_WARNING_REASON) {
+                        ic_cdk::println!("Failed to save a warning for {}: {:?}", principal, e);
                     }
                 }
+                Err(e)
             }
-        }
+        },
     }
 }
 
-pub fn get_warnings() -> Vec<Warning> {
-    let mut warnings: Vec<Warning> = vec![];
-
-    WARNED_USERS.with(|warning_store| {
-        warning_store.borrow().iter().for_each(|(_key, warning)| {
-            warnings.push(warning.clone());
-        })
-    });
-
-    warnings
+/// Forgets buckets that have refilled, since a full bucket is the same as no bucket
+pub fn evict_idle_buckets() {
+    let now = time();
+    RATE_LIMIT_STORE.with(|rate_limit| rate_limit.borrow_mut().retain(|_, full_at| *full_at > now));
 }
 
-fn get_calls(principal: Principal) -> Vec<Call> {
-    match RATE_LIMIT_STORE.with(|calls_store| calls_store.borrow().get(&principal).cloned()) {
-        Some(calls) => calls,
-        None => vec![],
+/// The limits come from the config, apart from the one day upload period
+fn get_bucket_config(bucket: Bucket) -> RateLimitConfig {
+    let config = get_config();
+    match bucket {
+        Bucket::Calls => RateLimitConfig {
+            limit: config.calls_per_window,
+            period: config.call_window,
+        },
+        Bucket::Uploads => RateLimitConfig {
+            limit: config.files_per_day,
+            period: FILES_REFRESH_RATE_ONE_DAY,
+        },
     }
 }
 
-fn get_number_of_warnings(principal: Principal) -> i128 {
-    match WARNED_USERS.with(|warning_store| warning_store.borrow().get(&principal).cloned()) {
-        Some(warning) => warning.number,
-        None => 0,
-    }
+/// How long until the bucket is full, zero if it already is
+fn get_time_until_full(principal: Principal, bucket: Bucket) -> u64 {
+    RATE_LIMIT_STORE
+        .with(|rate_limit| rate_limit.borrow().get(&(principal, bucket)).copied())
+        .map_or(0, |full_at| full_at.saturating_sub(time()))
 }
 
-fn insert_call(principal: Principal, call: Call) {
-    let mut new_calls = get_calls(principal);
-    new_calls.push(call);
+/// Checks a call can be taken from the bucket
+fn check_bucket(principal: Principal, bucket: Bucket) -> Result<(), AssetError> {
+    let time_until_full = get_time_until_full(principal, bucket);
 
-    RATE_LIMIT_STORE.with(|rate_limit| rate_limit.borrow_mut().insert(principal, new_calls));
+    match get_bucket_config(bucket).retry_after(time_until_full) {
+        Some(retry_after) => match bucket {
+            Bucket::Calls => Err(AssetError::RateLimited { retry_after }),
+            Bucket::Uploads => Err(AssetError::DailyUploadLimit { retry_after }),
+        },
+        None => Ok(()),
+    }
 }
 
-fn remove_last_create_call(principal: Principal, calls: Vec<Call>) {
-    let mut new_calls = calls.clone();
-    new_calls.sort_by(|a, b| b.time.cmp(&a.time));
-    let index = new_calls
-        .iter()
-        .position(|call| call.call_type == RateLimitMessageType::CreateFile);
-
-    match index {
-        Some(index) => {
-            new_calls.remove(index);
+fn take_from_bucket(principal: Principal, bucket: Bucket) {
+    let full_at = time()
+        + get_bucket_config(bucket)
+            .time_until_full_after_take(get_time_until_full(principal, bucket));
+    RATE_LIMIT_STORE
+        .with(|rate_limit| rate_limit.borrow_mut().insert((principal, bucket), full_at));
+}
 
-            RATE_LIMIT_STORE
-                .with(|rate_limit| rate_limit.borrow_mut().insert(principal, new_calls));
-        }
-        // Ignore this - we could get into a state where we call delete but there is nothing in the rate limit store
-        // Because the call has been cached
-        None => (),
+// If the bucket is already full this does nothing, we could get into a state where we call delete
+// but the create call was made before the bucket was last emptied
+fn give_back_to_bucket(principal: Principal, bucket: Bucket) {
+    let time_until_full = get_time_until_full(principal, bucket);
+    if time_until_full > 0 {
+        let full_at =
+            time() + get_bucket_config(bucket).time_until_full_after_give_back(time_until_full);
+        RATE_LIMIT_STORE
+            .with(|rate_limit| rate_limit.borrow_mut().insert((principal, bucket), full_at));
     }
 }
 
-fn insert_warning(principal: Principal) {
-    let number_of_warnings = get_number_of_warnings(principal);
+/// How many more files the principal can create today,
+/// and when the next of today's uploads stops counting towards the limit
+pub fn get_daily_upload_allowance(principal: Principal) -> (u64, Option<u64>) {
+    let config = get_bucket_config(Bucket::Uploads);
+    let time_until_full = get_time_until_full(principal, Bucket::Uploads);
+    let used = time_until_full.div_ceil(config.refill_interval());
 
-    WARNED_USERS.with(|warned_users| {
-        warned_users.borrow_mut().insert(
-            principal,
-            Warning {
-                number: number_of_warnings + 1,
-                principal,
-            },
-        )
-    });
+    (
+        config.limit.saturating_sub(used),
+        match time_until_full {
+            0 => None,
+            _ => Some(time() + (time_until_full - 1) % config.refill_interval() + 1),
+        },
+    )
 }
 
-// TODO: We should pull this whole file out and use the library instead,
-// But it's worth noting there is a slight difference here in that it checks something slightly different
-// I think we can put this in the library though to reuse code and have it all in just one place
-fn check_last_three_signals(calls: Vec<Call>) -> Result<String, String> {
-    let signal_calls = calls
-        .iter()
-        .filter(|call| call.call_type == RateLimitMessageType::CreateFile)
-        .collect::<Vec<_>>();
-    let mut sorted_calls = signal_calls.clone();
-    sorted_calls.sort_by(|a, b| b.time.cmp(&a.time));
-
-    match sorted_calls.get(FILES_PER_DAY as usize - 1) {
-        Some(call) => {
-            if less_than_a_day(call.time) {
-                return Err(String::from("Daily image limit reached"));
-            } else {
-                Ok(String::from("Rate limit okay"))
-            }
-        }
-        None => Ok(String::from("Rate limit okay")),
+/// When the principal can make calls again, None if they aren't rate limited
+pub fn get_rate_limited_until(principal: Principal) -> Option<u64> {
+    match check_bucket(principal, Bucket::Calls) {
+        Err(AssetError::RateLimited { retry_after }) => Some(time() + retry_after),
+        _ => None,
     }
 }
 
-fn check_rate_limit(calls: Vec<Call>) -> Result<String, String> {
-    let mut sorted_calls = calls.clone();
-    sorted_calls.sort_by(|a, b| b.time.cmp(&a.time));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match sorted_calls.get(CALL_RATE_LIMIT_WINDOW as usize) {
-        Some(call) => {
-            if less_than_a_minute(call.time) {
-                return Err(String::from("Rate limit reached"));
-            } else {
-                Ok(String::from("Rate limit okay"))
-            }
+    // Three calls every 300 nano seconds, one refilled every 100
+    const CONFIG: RateLimitConfig = RateLimitConfig {
+        limit: 3,
+        period: 300,
+    };
+
+    #[test]
+    fn full_bucket_allows_the_limit_then_refuses() {
+        let mut time_until_full = 0;
+        for _ in 0..CONFIG.limit {
+            assert_eq!(CONFIG.retry_after(time_until_full), None);
+            time_until_full = CONFIG.time_until_full_after_take(time_until_full);
         }
-        None => Ok(String::from("Rate limit okay")),
+
+        assert_eq!(time_until_full, 300);
+        assert_eq!(CONFIG.retry_after(time_until_full), Some(100));
     }
-}
 
-pub fn less_than_a_minute(call_time: u64) -> bool {
-    (time() - call_time) < RATE_LIMIT
-}
+    #[test]
+    fn empty_bucket_refills_one_call_at_a_time() {
+        // 30 nano seconds after being emptied
+        assert_eq!(CONFIG.retry_after(270), Some(70));
+        // A whole refill interval later one call can be taken again, but only one
+        assert_eq!(CONFIG.retry_after(200), None);
+        assert_eq!(
+            CONFIG.retry_after(CONFIG.time_until_full_after_take(200)),
+            Some(100)
+        );
+    }
 
-pub fn less_than_a_day(call_time: u64) -> bool {
-    (time() - call_time) < FILES_REFRESH_RATE_ONE_DAY
+    #[test]
+    fn giving_back_refills_a_call() {
+        assert_eq!(CONFIG.retry_after(300), Some(100));
+        assert_eq!(CONFIG.time_until_full_after_give_back(300), 200);
+        assert_eq!(CONFIG.retry_after(200), None);
+        assert_eq!(CONFIG.time_until_full_after_give_back(50), 0);
+    }
 }
@@ -0,0 +1,123 @@
+use ic_cdk::export::Principal;
+
+use crate::database::roles::get_stored_role;
+use crate::models::role::Role;
+
+use super::user::is_controller;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    // Give and take away roles
+    ManageRoles,
+    // Create, delete and sweep any user's files
+    ManageFiles,
+    // List any user's files and uploads
+    ViewFiles,
+    // Fetch and find private and unlisted files that weren't shared with them
+    ReadPrivateFiles,
+    // Set quotas
+    ManageUsers,
+    // See anyone's usage
+    ViewUsers,
+    // Block and unblock users
+    Moderate,
+    // See warnings, blocked users and deletion jobs
+    ViewModeration,
+    // See canister metrics
+    ViewMetrics,
+    // Skip rate limits, file size limits and quotas
+    BypassLimits,
+    // Change the rate limits and file size limits
+    ManageConfig,
+}
+
+/// The permission matrix
+pub fn role_has_permission(role: Role, permission: Permission) -> bool {
+    match role {
+        Role::Owner | Role::Admin => true,
+        Role::Moderator => matches!(
+            permission,
+            Permission::Moderate
+                | Permission::ViewModeration
+                | Permission::ViewFiles
+                | Permission::ViewUsers
+        ),
+        Role::Uploader => permission == Permission::BypassLimits,
+        Role::Reader => matches!(
+            permission,
+            Permission::ViewFiles
+                | Permission::ViewUsers
+                | Permission::ViewModeration
+                | Permission::ViewMetrics
+        ),
+    }
+}
+
+/// Controllers are always owners, everyone else has the role they were given, if any
+pub fn get_role(principal: Principal) -> Option<Role> {
+    match is_controller(&principal) {
+        true => Some(Role::Owner),
+        false => get_stored_role(principal),
+    }
+}
+
+pub fn has_permission(principal: Principal, permission: Permission) -> bool {
+    get_role(principal).is_some_and(|role| role_has_permission(role, permission))
+}
+
+/// Owners can give or take away any role, admins only those below their own
+pub fn can_manage_role(manager: Principal, role: Role) -> bool {
+    match get_role(manager) {
+        Some(Role::Owner) => true,
+        Some(manager_role) => {
+            role_has_permission(manager_role, Permission::ManageRoles) && role > manager_role
+        }
+        None => false,
+    }
+}
+
+/// Moderators can only block and delete users whose role is below their own
+pub fn can_moderate(moderator: Principal, target: Principal) -> bool {
+    match get_role(moderator) {
+        Some(moderator_role) => outranks(moderator_role, get_role(target)),
+        None => false,
+    }
+}
+
+/// Users without a role rank below every role
+fn outranks(role: Role, other: Option<Role>) -> bool {
+    other.is_none_or(|other| other > role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_owners_and_admins_read_private_files() {
+        let readers: Vec<Role> = [
+            Role::Owner,
+            Role::Admin,
+            Role::Moderator,
+            Role::Uploader,
+            Role::Reader,
+        ]
+        .into_iter()
+        .filter(|role| role_has_permission(*role, Permission::ReadPrivateFiles))
+        .collect();
+
+        assert_eq!(readers, vec![Role::Owner, Role::Admin]);
+        assert!(role_has_permission(Role::Reader, Permission::ViewFiles));
+    }
+
+    #[test]
+    fn only_lower_roles_can_be_moderated() {
+        assert!(outranks(Role::Moderator, None));
+        assert!(outranks(Role::Moderator, Some(Role::Uploader)));
+        assert!(outranks(Role::Owner, Some(Role::Admin)));
+        assert!(!outranks(Role::Moderator, Some(Role::Moderator)));
+        assert!(!outranks(Role::Moderator, Some(Role::Admin)));
+        assert!(!outranks(Role::Admin, Some(Role::Owner)));
+        assert!(!outranks(Role::Owner, Some(Role::Owner)));
+    }
+}
@@ -0,0 +1,243 @@
+use ic_cdk::api::{management_canister::main::raw_rand, time};
+use ic_stable_structures::cell::Cell as StableCell;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::{
+    api::error::AssetError,
+    database::{
+        certification::file_route,
+        file::FileID,
+        memory::{get_memory, Memory, SHARE_LINK_SECRET_MEMORY_ID},
+    },
+    models::file::{File, Hash},
+};
+
+/// Share links can last at most 30 days
+pub const MAX_SHARE_LINK_TTL: u64 = 30 * 24 * 60 * 60;
+
+const HMAC_BLOCK_SIZE: usize = 64;
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+thread_local! {
+    // Key for signing share links, empty until the first link is created
+    static SHARE_LINK_SECRET: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(get_memory(SHARE_LINK_SECRET_MEMORY_ID), vec![])
+            .expect("Failed to initialise the share link secret")
+    );
+}
+
+/// The query string of a share link
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareLink {
+    // Seconds since the epoch
+    pub expires_at: u64,
+    // Hex encoded HMAC-SHA256 of the file and expiry
+    pub token: String,
+}
+
+impl ShareLink {
+    pub fn query_string(&self) -> String {
+        format!("token={}&exp={}", self.token, self.expires_at)
+    }
+}
+
+/// Builds a link to the file that works until `expires_in` seconds from now
+/// The secret is drawn from `raw_rand` the first time a link is created
+pub async fn create_share_link(file: &File, expires_in: u64) -> Result<String, AssetError> {
+    let secret = match get_secret() {
+        Some(secret) => secret,
+        None => generate_secret().await?,
+    };
+
+    let expires_at = time() / NANOSECONDS_PER_SECOND + expires_in.min(MAX_SHARE_LINK_TTL);
+    let share_link = ShareLink {
+        expires_at,
+        token: sign(&secret, file.id, file.get_share_salt(), expires_at),
+    };
+
+    Ok(format!(
+        "{}?{}",
+        file_route(file),
+        share_link.query_string()
+    ))
+}
+
+/// Checks a share link was signed for the file's current salt and hasn't expired
+pub fn verify_share_link(file_id: FileID, salt: u64, share_link: &ShareLink) -> bool {
+    match get_secret() {
+        Some(secret) => verify_signature(
+            &secret,
+            file_id,
+            salt,
+            share_link,
+            time() / NANOSECONDS_PER_SECOND,
+        ),
+        None => false,
+    }
+}
+
+fn verify_signature(
+    secret: &[u8],
+    file_id: FileID,
+    salt: u64,
+    share_link: &ShareLink,
+    now_in_seconds: u64,
+) -> bool {
+    if share_link.expires_at < now_in_seconds {
+        return false;
+    }
+
+    let expected = sign(secret, file_id, salt, share_link.expires_at);
+    constant_time_eq(expected.as_bytes(), share_link.token.as_bytes())
+}
+
+/// Reads `token` and `exp` out of a query string
+pub fn parse_share_link(query: &str) -> Option<ShareLink> {
+    let mut token = None;
+    let mut expires_at = None;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "token" => token = Some(value.to_string()),
+            "exp" => expires_at = value.parse::<u64>().ok(),
+            _ => (),
+        }
+    }
+
+    Some(ShareLink {
+        expires_at: expires_at?,
+        token: token?,
+    })
+}
+
+fn get_secret() -> Option<Vec<u8>> {
+    let secret = SHARE_LINK_SECRET.with(|secret| secret.borrow().get().clone());
+    match secret.is_empty() {
+        true => None,
+        false => Some(secret),
+    }
+}
+
+async fn generate_secret() -> Result<Vec<u8>, AssetError> {
+    let (bytes,) = match raw_rand().await {
+        Ok(bytes) => bytes,
+        Err((_, message)) => return Err(AssetError::StorageError(message)),
+    };
+
+    // Another call may have set the secret while this one waited for randomness
+    if let Some(secret) = get_secret() {
+        return Ok(secret);
+    }
+
+    match SHARE_LINK_SECRET.with(|secret| secret.borrow_mut().set(bytes.clone())) {
+        Ok(_) => Ok(bytes),
+        Err(_) => Err(AssetError::StorageError(String::from(
+            "Failed to save the share link secret",
+        ))),
+    }
+}
+
+fn sign(secret: &[u8], file_id: FileID, salt: u64, expires_at: u64) -> String {
+    let message = format!("{}:{}:{}", file_id, salt, expires_at);
+    hmac_sha256(secret, message.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// HMAC as described in RFC 2104
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Hash {
+    let mut block = [0u8; HMAC_BLOCK_SIZE];
+    if key.len() > HMAC_BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // Test cases 1, 2 and 6 of RFC 4231
+    #[test]
+    fn hmac_matches_the_rfc_test_vectors() {
+        assert_eq!(
+            hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    fn share_link(secret: &[u8], file_id: FileID, salt: u64, expires_at: u64) -> ShareLink {
+        ShareLink {
+            expires_at,
+            token: sign(secret, file_id, salt, expires_at),
+        }
+    }
+
+    #[test]
+    fn signed_link_is_valid_until_it_expires() {
+        let link = share_link(b"secret", 1, 0, 1000);
+        assert!(verify_signature(b"secret", 1, 0, &link, 999));
+        assert!(verify_signature(b"secret", 1, 0, &link, 1000));
+        assert!(!verify_signature(b"secret", 1, 0, &link, 1001));
+    }
+
+    #[test]
+    fn link_is_only_valid_for_its_file_salt_and_secret() {
+        let link = share_link(b"secret", 1, 0, 1000);
+        assert!(!verify_signature(b"secret", 2, 0, &link, 0));
+        assert!(!verify_signature(b"other secret", 1, 0, &link, 0));
+        // Changing the file's salt is how every link to it is revoked
+        assert!(!verify_signature(b"secret", 1, 1, &link, 0));
+
+        let extended = ShareLink {
+            expires_at: 2000,
+            ..link
+        };
+        assert!(!verify_signature(b"secret", 1, 0, &extended, 0));
+    }
+
+    #[test]
+    fn parses_the_query_string_it_builds() {
+        let link = share_link(b"secret", 1, 0, 1000);
+        assert_eq!(parse_share_link(&link.query_string()), Some(link));
+        assert_eq!(
+            parse_share_link("v=2&exp=5&token=abc"),
+            Some(ShareLink {
+                expires_at: 5,
+                token: String::from("abc"),
+            })
+        );
+        assert_eq!(parse_share_link("token=abc"), None);
+        assert_eq!(parse_share_link("token=abc&exp=soon"), None);
+    }
+}
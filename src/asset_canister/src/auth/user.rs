@@ -1,37 +1,59 @@
 use ic_cdk::export::Principal;
 
-include!("../../../../env/admins.rs");
+use crate::api::error::AssetError;
 
-pub fn get_logged_in_principal() -> Result<Principal, String> {
+use super::roles::{has_permission, Permission};
+
+// ic-cdk 0.6 doesn't wrap `ic0.is_controller`, so it is imported directly
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "ic0")]
+extern "C" {
+    #[link_name = "is_controller"]
+    fn ic0_is_controller(src: usize, size: usize) -> u32;
+}
+
+/// Whether the principal is a controller of this canister
+#[cfg(target_arch = "wasm32")]
+pub fn is_controller(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+    // SAFETY: the system only reads `size` bytes from `src`, which point into `bytes`
+    unsafe { ic0_is_controller(bytes.as_ptr() as usize, bytes.len()) == 1 }
+}
+
+/// There are no controllers outside of the IC
+#[cfg(not(target_arch = "wasm32"))]
+pub fn is_controller(_principal: &Principal) -> bool {
+    false
+}
+
+pub fn get_logged_in_principal() -> Result<Principal, AssetError> {
     let caller = ic_cdk::api::caller();
     // The anonymous principal is not allowed to do certain actions
     if caller == Principal::anonymous() {
-        return Err(String::from(
-            "Anonymous principal not allowed to make calls.",
-        ));
+        return Err(AssetError::Anonymous);
     }
 
     Ok(caller)
 }
 
-pub fn get_logged_in_superuser() -> Result<Principal, String> {
-    let caller = ic_cdk::api::caller();
-    // The anonymous principal is not allowed to do certain actions
-    if caller == Principal::anonymous() {
-        return Err(String::from(
-            "Anonymous principal not allowed to make calls.",
-        ));
+/// Callers whose role grants the permission
+pub fn get_logged_in_with_permission(permission: Permission) -> Result<Principal, AssetError> {
+    match get_logged_in_principal() {
+        Ok(caller) => match has_permission(caller, permission) {
+            true => Ok(caller),
+            false => Err(AssetError::Unauthorized),
+        },
+        Err(e) => Err(e),
     }
+}
 
-    if option_env!("DFX_NETWORK") == Some("local") {
-        match DEV_ADMINS.contains(&caller.to_string().as_str()) {
-            true => Ok(caller),
-            false => Err(String::from("Unauthorised")),
-        }
-    } else {
-        match PROD_ADMINS.contains(&caller.to_string().as_str()) {
+/// Only controllers can manage the admin list directly
+pub fn get_logged_in_controller() -> Result<Principal, AssetError> {
+    match get_logged_in_principal() {
+        Ok(caller) => match is_controller(&caller) {
             true => Ok(caller),
-            false => Err(String::from("Unauthorised")),
-        }
+            false => Err(AssetError::Unauthorized),
+        },
+        Err(e) => Err(e),
     }
 }
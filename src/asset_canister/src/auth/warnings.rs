@@ -0,0 +1,328 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    api::error::AssetError,
+    database::{
+        config::get_config,
+        memory::{get_memory, Memory, WARNINGS_BY_TIME_MEMORY_ID, WARNINGS_MEMORY_ID},
+    },
+};
+
+// Keys are the principal's length, the principal (at most 29 bytes), the time it was warned
+// and a sequence number for warnings given in the same round
+const MAX_KEY_SIZE: u32 = 42;
+// Time index keys are the time followed by the warning's key
+const MAX_TIME_KEY_SIZE: u32 = 8 + MAX_KEY_SIZE;
+// Room for the longest reason, 200 characters of up to 4 bytes each
+const MAX_VALUE_SIZE: u32 = 900;
+const MAX_REASON_LENGTH: usize = 200;
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct WarningEntry {
+    pub time: u64,
+    pub reason: String,
+}
+
+/// The warnings of a principal that haven't decayed yet
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Warning {
+    pub number: i128,
+    pub principal: Principal,
+    pub warnings: Vec<WarningEntry>,
+}
+
+// The time is kept in the key, so only the reason is stored
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredWarning {
+    reason: String,
+}
+
+impl Storable for StoredWarning {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+thread_local! {
+    static WARNINGS: RefCell<StableBTreeMap<Memory, Vec<u8>, StoredWarning>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(WARNINGS_MEMORY_ID),
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+    );
+
+    // The same warnings oldest first, so decayed ones are found without reading the rest
+    static WARNINGS_BY_TIME: RefCell<StableBTreeMap<Memory, Vec<u8>, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(WARNINGS_BY_TIME_MEMORY_ID),
+            MAX_TIME_KEY_SIZE,
+            0
+        )
+    );
+}
+
+pub fn insert_warning(principal: Principal, reason: &str) -> Result<(), AssetError> {
+    let reason = reason.chars().take(MAX_REASON_LENGTH).collect();
+    store_warning(
+        next_warning_key(principal, time()),
+        StoredWarning { reason },
+    )
+}
+
+fn store_warning(key: Vec<u8>, warning: StoredWarning) -> Result<(), AssetError> {
+    WARNINGS
+        .with(|warnings| warnings.borrow_mut().insert(key.clone(), warning))
+        .and_then(|_| {
+            WARNINGS_BY_TIME.with(|by_time| by_time.borrow_mut().insert(time_index_key(&key), ()))
+        })
+        .map(|_| ())
+        .map_err(|e| AssetError::StorageError(e.to_string()))
+}
+
+/// Every call made in the same round sees the same time, so those warnings are numbered
+fn next_warning_key(principal: Principal, time: u64) -> Vec<u8> {
+    let mut prefix = warning_prefix(principal);
+    prefix.extend_from_slice(&time.to_be_bytes());
+    let sequence = WARNINGS.with(|warnings| warnings.borrow().range(prefix, None).count());
+    warning_key(principal, time, sequence as u32)
+}
+
+/// How many warnings the principal has had within the decay period
+pub fn get_number_of_warnings(principal: Principal) -> u64 {
+    let decayed_before = decayed_before();
+    WARNINGS.with(|warnings| {
+        warnings
+            .borrow()
+            .range(warning_prefix(principal), None)
+            .filter(|(key, _)| time_from_key(key) >= decayed_before)
+            .count() as u64
+    })
+}
+
+pub fn get_warnings() -> Vec<Warning> {
+    let decayed_before = decayed_before();
+    let mut warnings: Vec<Warning> = vec![];
+
+    WARNINGS.with(|warning_store| {
+        warning_store
+            .borrow()
+            .iter()
+            .filter(|(key, _)| time_from_key(key) >= decayed_before)
+            .for_each(|(key, warning)| {
+                let principal = principal_from_key(&key);
+                let entry = WarningEntry {
+                    time: time_from_key(&key),
+                    reason: warning.reason,
+                };
+                // Keys are grouped by principal, so only the last one needs checking
+                match warnings.last_mut() {
+                    Some(last) if last.principal == principal => {
+                        last.number += 1;
+                        last.warnings.push(entry);
+                    }
+                    _ => warnings.push(Warning {
+                        number: 1,
+                        principal,
+                        warnings: vec![entry],
+                    }),
+                }
+            })
+    });
+
+    warnings
+}
+
+/// Removes every warning of the principal, decayed or not
+pub fn reset_warnings(principal: Principal) {
+    let keys: Vec<Vec<u8>> = WARNINGS.with(|warnings| {
+        warnings
+            .borrow()
+            .range(warning_prefix(principal), None)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    remove_warnings(keys);
+}
+
+/// Removes up to `limit` decayed warnings and returns how many were removed
+pub fn prune_decayed_warnings(limit: usize) -> usize {
+    let keys = get_warning_keys_before(decayed_before(), limit);
+    let number_removed = keys.len();
+    remove_warnings(keys);
+    number_removed
+}
+
+fn get_warning_keys_before(decayed_before: u64, limit: usize) -> Vec<Vec<u8>> {
+    WARNINGS_BY_TIME.with(|by_time| {
+        by_time
+            .borrow()
+            .iter()
+            .take_while(|(time_key, _)| time_from_key(&time_key[8..]) < decayed_before)
+            .take(limit)
+            .map(|(time_key, _)| time_key[8..].to_vec())
+            .collect()
+    })
+}
+
+fn remove_warnings(keys: Vec<Vec<u8>>) {
+    WARNINGS.with(|warnings| {
+        let mut warnings = warnings.borrow_mut();
+        keys.iter().for_each(|key| {
+            warnings.remove(key);
+        });
+    });
+    WARNINGS_BY_TIME.with(|by_time| {
+        let mut by_time = by_time.borrow_mut();
+        keys.iter().for_each(|key| {
+            by_time.remove(&time_index_key(key));
+        });
+    });
+}
+
+fn decayed_before() -> u64 {
+    time().saturating_sub(get_config().warning_decay)
+}
+
+fn warning_prefix(principal: Principal) -> Vec<u8> {
+    let principal = principal.as_slice();
+    let mut prefix = vec![principal.len() as u8];
+    prefix.extend_from_slice(principal);
+    prefix
+}
+
+// Times are big-endian so that each principal's warnings sort oldest first
+fn warning_key(principal: Principal, time: u64, sequence: u32) -> Vec<u8> {
+    let mut key = warning_prefix(principal);
+    key.extend_from_slice(&time.to_be_bytes());
+    key.extend_from_slice(&sequence.to_be_bytes());
+    key
+}
+
+fn time_index_key(key: &[u8]) -> Vec<u8> {
+    let mut time_key = time_from_key(key).to_be_bytes().to_vec();
+    time_key.extend_from_slice(key);
+    time_key
+}
+
+fn principal_from_key(key: &[u8]) -> Principal {
+    Principal::from_slice(&key[1..1 + key[0] as usize])
+}
+
+fn time_from_key(key: &[u8]) -> u64 {
+    let start = 1 + key[0] as usize;
+    let mut time = [0; 8];
+    time.copy_from_slice(&key[start..start + 8]);
+    u64::from_be_bytes(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn key_holds_the_principal_and_time() {
+        let key = warning_key(principal(1), 42, 3);
+        assert_eq!(key.len(), MAX_KEY_SIZE as usize);
+        assert_eq!(principal_from_key(&key), principal(1));
+        assert_eq!(time_from_key(&key), 42);
+
+        let key = warning_key(Principal::anonymous(), 7, 0);
+        assert_eq!(principal_from_key(&key), Principal::anonymous());
+        assert_eq!(time_from_key(&key), 7);
+    }
+
+    #[test]
+    fn keys_sort_by_principal_then_time_then_sequence() {
+        let mut keys = vec![
+            warning_key(principal(2), 1, 0),
+            warning_key(principal(1), 256, 0),
+            warning_key(principal(1), 2, 1),
+            warning_key(principal(1), 2, 0),
+        ];
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                warning_key(principal(1), 2, 0),
+                warning_key(principal(1), 2, 1),
+                warning_key(principal(1), 256, 0),
+                warning_key(principal(2), 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn warnings_in_the_same_round_are_all_kept() {
+        for reason in ["first", "second", "third"] {
+            let key = next_warning_key(principal(1), 100);
+            let reason = String::from(reason);
+            store_warning(key, StoredWarning { reason }).unwrap();
+        }
+        let key = next_warning_key(principal(1), 101);
+        assert_eq!(key, warning_key(principal(1), 101, 0));
+
+        let number = WARNINGS.with(|warnings| {
+            warnings
+                .borrow()
+                .range(warning_prefix(principal(1)), None)
+                .count()
+        });
+        assert_eq!(number, 3);
+    }
+
+    #[test]
+    fn decayed_warnings_are_found_oldest_first_across_principals() {
+        [(2, 300), (3, 100), (4, 200)]
+            .into_iter()
+            .for_each(|(byte, time)| {
+                let reason = String::from("spam");
+                store_warning(
+                    warning_key(principal(byte), time, 0),
+                    StoredWarning { reason },
+                )
+                .unwrap();
+            });
+
+        let keys = get_warning_keys_before(250, 10);
+        let times: Vec<u64> = keys.iter().map(|key| time_from_key(key)).collect();
+        assert_eq!(times, vec![100, 200]);
+        assert_eq!(get_warning_keys_before(250, 1).len(), 1);
+
+        remove_warnings(keys);
+        assert!(get_warning_keys_before(250, 10).is_empty());
+        let remaining: Vec<Principal> = WARNINGS.with(|warnings| {
+            warnings
+                .borrow()
+                .iter()
+                .map(|(key, _)| principal_from_key(&key))
+                .collect()
+        });
+        assert_eq!(remaining, vec![principal(2)]);
+    }
+
+    #[test]
+    fn longest_reason_can_be_stored() {
+        let reason = "\u{10FFFF}".repeat(MAX_REASON_LENGTH);
+        assert!(
+            StoredWarning {
+                reason: reason.clone()
+            }
+            .to_bytes()
+            .len()
+                <= MAX_VALUE_SIZE as usize
+        );
+        store_warning(warning_key(principal(5), 1, 0), StoredWarning { reason }).unwrap();
+    }
+}
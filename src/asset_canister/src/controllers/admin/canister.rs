@@ -1,24 +1,28 @@
+use candid::candid_method;
 use ic_cdk_macros::*;
 
 use crate::{
-    api::canister::CanisterInfo,
+    api::{canister::CanisterInfo, error::AssetError},
     auth::{
-        canister::canister_storage_ok as be_canister_storage_ok, user::get_logged_in_superuser,
+        canister::canister_storage_ok as be_canister_storage_ok, roles::Permission,
+        user::get_logged_in_with_permission,
     },
     metrics::metrics::collect_metrics as be_collect_metrics,
 };
 
 #[update]
-pub fn collect_metrics() -> Result<CanisterInfo, String> {
-    match get_logged_in_superuser() {
+#[candid_method(update)]
+pub fn collect_metrics() -> Result<CanisterInfo, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewMetrics) {
         Ok(_) => Ok(be_collect_metrics()),
         Err(e) => Err(e),
     }
 }
 
 #[query]
-pub fn canister_storage_ok() -> Result<u64, String> {
-    match get_logged_in_superuser() {
+#[candid_method(query)]
+pub fn canister_storage_ok() -> Result<u64, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewMetrics) {
         Ok(_) => be_canister_storage_ok(),
         Err(e) => Err(e),
     }
@@ -0,0 +1,35 @@
+use candid::candid_method;
+use ic_cdk_macros::*;
+
+use crate::{
+    api::error::AssetError,
+    auth::{roles::Permission, user::get_logged_in_with_permission},
+    database::{
+        audit::{record_action, AuditAction, AuditTarget},
+        config::{get_config as be_get_config, set_config as be_set_config},
+    },
+    models::config::Config,
+};
+
+/// Replaces the config, which is checked first
+#[update]
+#[candid_method(update)]
+pub fn set_config(config: Config) -> Result<Config, AssetError> {
+    match get_logged_in_with_permission(Permission::ManageConfig) {
+        Ok(admin) => match be_set_config(config) {
+            Ok(config) => {
+                record_action(admin, AuditAction::SetConfig, AuditTarget::Canister, None);
+                Ok(config)
+            }
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Anyone can see the config, clients need the chunk size to split uploads
+#[query]
+#[candid_method(query)]
+pub fn get_config() -> Config {
+    be_get_config()
+}
@@ -1,27 +1,41 @@
-use candid::Principal;
+use candid::{candid_method, Principal};
 use ic_cdk_macros::*;
 use serde_bytes::ByteBuf;
 
 use crate::{
-    api::file::FEFile,
+    api::{
+        error::AssetError,
+        file::{FEFile, FileFilter, FilePage},
+    },
     auth::{
         file::{chunk_size_okay, file_size_accepted},
-        user::get_logged_in_superuser,
+        roles::Permission,
+        user::get_logged_in_with_permission,
+    },
+    database::{
+        audit::{record_action, AuditAction, AuditTarget},
+        config::get_config,
+        file::{create_file as be_create_file, list_files as be_list_files, FileID},
+        uploads::{
+            get_stale_uploads as be_get_stale_uploads,
+            sweep_stale_uploads as be_sweep_stale_uploads,
+        },
     },
-    database::file::create_file as be_create_file,
 };
 
 #[update]
+#[candid_method(update)]
 pub fn prune_file(
     first_chunk: ByteBuf,
     file_name: String,
     number_of_chunks: u64,
     file_type: String,
     principal: Principal,
-) -> Result<FEFile, String> {
-    match get_logged_in_superuser() {
-        Ok(_) => match file_size_accepted(number_of_chunks) {
-            Ok(_) => match chunk_size_okay(first_chunk.len() as usize) {
+    reason: Option<String>,
+) -> Result<FEFile, AssetError> {
+    match get_logged_in_with_permission(Permission::ManageFiles) {
+        Ok(admin) => match file_size_accepted(number_of_chunks) {
+            Ok(_) => match chunk_size_okay(first_chunk.len(), get_config().chunk_size) {
                 Ok(_) => match be_create_file(
                     first_chunk,
                     file_name,
@@ -29,7 +43,18 @@ pub fn prune_file(
                     file_type,
                     principal,
                 ) {
-                    Ok(file) => Ok(file.create_fe_type()),
+                    Ok(file) => {
+                        record_action(
+                            admin,
+                            AuditAction::PruneFile,
+                            AuditTarget::File {
+                                file_id: file.id,
+                                owner: principal,
+                            },
+                            reason,
+                        );
+                        Ok(file.create_fe_type())
+                    }
                     Err(e) => Err(e),
                 },
                 Err(e) => Err(e),
@@ -40,3 +65,43 @@ pub fn prune_file(
         Err(e) => Err(e),
     }
 }
+
+#[query]
+#[candid_method(query)]
+pub fn list_files(
+    filter: FileFilter,
+    cursor: Option<ByteBuf>,
+    limit: u64,
+) -> Result<FilePage, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewFiles) {
+        Ok(_) => match be_list_files(&filter, cursor.map(|c| c.into_vec()), limit as usize) {
+            Ok((files, next_cursor)) => Ok(FilePage {
+                files: files.iter().map(|file| file.create_fe_type()).collect(),
+                next_cursor: next_cursor.map(ByteBuf::from),
+            }),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_stale_uploads() -> Result<Vec<FEFile>, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewFiles) {
+        Ok(_) => Ok(be_get_stale_uploads()
+            .iter()
+            .map(|file| file.create_fe_type())
+            .collect()),
+        Err(e) => Err(e),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+pub fn sweep_stale_uploads(limit: u64) -> Result<Vec<FileID>, AssetError> {
+    match get_logged_in_with_permission(Permission::ManageFiles) {
+        Ok(_) => Ok(be_sweep_stale_uploads(limit as usize)),
+        Err(e) => Err(e),
+    }
+}
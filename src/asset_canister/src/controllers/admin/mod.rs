@@ -1,3 +1,6 @@
 pub mod canister;
+pub mod config;
 pub mod file;
 pub mod moderation;
+pub mod roles;
+pub mod user;
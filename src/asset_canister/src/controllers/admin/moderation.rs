@@ -1,40 +1,81 @@
+use candid::candid_method;
 use ic_cdk::export::Principal;
 use ic_cdk_macros::*;
 
+use crate::api::audit::AuditLogPage;
+use crate::api::error::AssetError;
+use crate::auth::appeals::{
+    get_appeals as be_get_appeals, resolve_appeal as be_resolve_appeal, Appeal, AppealStatus,
+};
 use crate::auth::moderation::{
     block_and_delete_user as be_block_and_delete_user, block_user as be_block_user,
     get_blocked_users as be_get_blocked_users, unblock_user as be_unblock_user,
 };
-use crate::auth::ratelimit::{get_warnings as be_get_warnings, Warning};
-use crate::auth::user::get_logged_in_superuser;
+use crate::auth::roles::{can_moderate, Permission};
+use crate::auth::user::get_logged_in_with_permission;
+use crate::auth::warnings::{get_warnings as be_get_warnings, Warning};
+use crate::database::audit::{
+    get_audit_log as be_get_audit_log, record_action, AuditAction, AuditTarget,
+};
+use crate::jobs::deletion::{get_deletion_jobs as be_get_deletion_jobs, DeletionJob};
 
 #[update]
-pub fn block_user(principal: Principal) -> Result<String, String> {
-    match get_logged_in_superuser() {
-        Ok(_) => {
-            be_block_user(principal);
-            Ok(String::from("User blocked"))
-        }
+#[candid_method(update)]
+pub fn block_user(principal: Principal, reason: Option<String>) -> Result<String, AssetError> {
+    match get_logged_in_with_permission(Permission::Moderate) {
+        Ok(moderator) => match can_moderate(moderator, principal) {
+            true => {
+                be_block_user(principal, reason.clone().unwrap_or_default());
+                record_action(
+                    moderator,
+                    AuditAction::BlockUser,
+                    AuditTarget::User(principal),
+                    reason,
+                );
+                Ok(String::from("User blocked"))
+            }
+            false => Err(AssetError::Unauthorized),
+        },
         Err(e) => Err(e),
     }
 }
 
 #[update]
-pub fn block_and_delete_user(principal: Principal) -> Result<String, String> {
-    match get_logged_in_superuser() {
-        Ok(_) => {
-            be_block_and_delete_user(principal);
-            Ok(String::from("User blocked and deleted"))
-        }
+#[candid_method(update)]
+pub fn block_and_delete_user(
+    principal: Principal,
+    reason: Option<String>,
+) -> Result<String, AssetError> {
+    match get_logged_in_with_permission(Permission::Moderate) {
+        Ok(moderator) => match can_moderate(moderator, principal) {
+            true => {
+                be_block_and_delete_user(principal, reason.clone().unwrap_or_default());
+                record_action(
+                    moderator,
+                    AuditAction::BlockAndDeleteUser,
+                    AuditTarget::User(principal),
+                    reason,
+                );
+                Ok(String::from("User blocked and file deletion started"))
+            }
+            false => Err(AssetError::Unauthorized),
+        },
         Err(e) => Err(e),
     }
 }
 
 #[update]
-pub fn unblock_user(principal: Principal) -> Result<String, String> {
-    match get_logged_in_superuser() {
-        Ok(_) => {
+#[candid_method(update)]
+pub fn unblock_user(principal: Principal, reason: Option<String>) -> Result<String, AssetError> {
+    match get_logged_in_with_permission(Permission::Moderate) {
+        Ok(moderator) => {
             be_unblock_user(principal);
+            record_action(
+                moderator,
+                AuditAction::UnblockUser,
+                AuditTarget::User(principal),
+                reason,
+            );
             Ok(String::from("User unblocked"))
         }
         Err(e) => Err(e),
@@ -42,17 +83,78 @@ pub fn unblock_user(principal: Principal) -> Result<String, String> {
 }
 
 #[query]
-pub fn get_warnings() -> Result<Vec<Warning>, String> {
-    match get_logged_in_superuser() {
+#[candid_method(query)]
+pub fn get_warnings() -> Result<Vec<Warning>, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewModeration) {
         Ok(_) => Ok(be_get_warnings()),
         Err(e) => Err(e),
     }
 }
 
 #[query]
-pub fn get_blocked_users() -> Result<Vec<Principal>, String> {
-    match get_logged_in_superuser() {
+#[candid_method(query)]
+pub fn get_blocked_users() -> Result<Vec<Principal>, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewModeration) {
         Ok(_) => Ok(be_get_blocked_users()),
         Err(e) => Err(e),
     }
 }
+
+#[query]
+#[candid_method(query)]
+pub fn get_deletion_jobs() -> Result<Vec<DeletionJob>, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewModeration) {
+        Ok(_) => Ok(be_get_deletion_jobs()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Lists appeals with the given status, or every appeal
+#[query]
+#[candid_method(query)]
+pub fn list_appeals(status: Option<AppealStatus>) -> Result<Vec<Appeal>, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewModeration) {
+        Ok(_) => Ok(be_get_appeals(status)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Accepting an appeal unblocks the user and clears their warnings
+#[update]
+#[candid_method(update)]
+pub fn resolve_appeal(
+    principal: Principal,
+    accept: bool,
+    reason: Option<String>,
+) -> Result<Appeal, AssetError> {
+    match get_logged_in_with_permission(Permission::Moderate) {
+        Ok(moderator) => match be_resolve_appeal(principal, accept, moderator) {
+            Ok(appeal) => {
+                let action = match accept {
+                    true => AuditAction::AcceptAppeal,
+                    false => AuditAction::RejectAppeal,
+                };
+                record_action(moderator, action, AuditTarget::User(principal), reason);
+                Ok(appeal)
+            }
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the audit log newest first, pass `next_before` back to get the next page
+#[query]
+#[candid_method(query)]
+pub fn get_audit_log(before: Option<u64>, limit: u64) -> Result<AuditLogPage, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewModeration) {
+        Ok(_) => {
+            let (entries, next_before) = be_get_audit_log(before, limit as usize);
+            Ok(AuditLogPage {
+                entries,
+                next_before,
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,128 @@
+use candid::{candid_method, Principal};
+use ic_cdk_macros::*;
+
+use crate::{
+    api::{error::AssetError, user::RoleAssignment},
+    auth::{
+        roles::{can_manage_role, get_role, Permission},
+        user::{get_logged_in_controller, get_logged_in_principal, get_logged_in_with_permission},
+    },
+    database::{
+        audit::{record_action, AuditAction, AuditTarget},
+        roles::{get_roles as be_get_roles, get_stored_role, remove_role, set_role as be_set_role},
+    },
+    models::role::Role,
+};
+
+/// Gives a user a role, or takes it away with None
+/// Admins can only manage the roles below their own
+#[update]
+#[candid_method(update)]
+pub fn set_role(principal: Principal, role: Option<Role>) -> Result<Option<Role>, AssetError> {
+    match get_logged_in_with_permission(Permission::ManageRoles) {
+        Ok(caller) => {
+            let can_change_current = get_stored_role(principal)
+                .is_none_or(|current_role| can_manage_role(caller, current_role));
+            let can_give_new = role.is_none_or(|role| can_manage_role(caller, role));
+
+            match can_change_current && can_give_new {
+                true => {
+                    match role {
+                        Some(role) => be_set_role(principal, role),
+                        None => {
+                            remove_role(principal);
+                        }
+                    }
+                    record_action(
+                        caller,
+                        AuditAction::SetRole,
+                        AuditTarget::User(principal),
+                        None,
+                    );
+                    Ok(get_role(principal))
+                }
+                false => Err(AssetError::Unauthorized),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn list_roles() -> Result<Vec<RoleAssignment>, AssetError> {
+    match get_logged_in_with_permission(Permission::ManageRoles) {
+        Ok(_) => Ok(be_get_roles()
+            .into_iter()
+            .map(|(principal, role)| RoleAssignment { principal, role })
+            .collect()),
+        Err(e) => Err(e),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_my_role() -> Result<Option<Role>, AssetError> {
+    match get_logged_in_principal() {
+        Ok(principal) => Ok(get_role(principal)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Gives a user the admin role, controllers can do this whatever their stored role
+#[update]
+#[candid_method(update)]
+pub fn add_admin(principal: Principal) -> Result<Vec<Principal>, AssetError> {
+    match get_logged_in_controller() {
+        Ok(controller) => {
+            be_set_role(principal, Role::Admin);
+            record_action(
+                controller,
+                AuditAction::SetRole,
+                AuditTarget::User(principal),
+                None,
+            );
+            Ok(get_admins())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Takes the admin role away from a user, other roles are left alone
+#[update]
+#[candid_method(update)]
+pub fn remove_admin(principal: Principal) -> Result<Vec<Principal>, AssetError> {
+    match get_logged_in_controller() {
+        Ok(controller) => {
+            if get_stored_role(principal) == Some(Role::Admin) {
+                remove_role(principal);
+                record_action(
+                    controller,
+                    AuditAction::SetRole,
+                    AuditTarget::User(principal),
+                    None,
+                );
+            }
+            Ok(get_admins())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Users with the admin role, controllers are owners without being listed
+#[query]
+#[candid_method(query)]
+pub fn list_admins() -> Result<Vec<Principal>, AssetError> {
+    match get_logged_in_controller() {
+        Ok(_) => Ok(get_admins()),
+        Err(e) => Err(e),
+    }
+}
+
+fn get_admins() -> Vec<Principal> {
+    be_get_roles()
+        .into_iter()
+        .filter(|(_, role)| *role == Role::Admin)
+        .map(|(principal, _)| principal)
+        .collect()
+}
@@ -0,0 +1,57 @@
+use candid::{candid_method, Principal};
+use ic_cdk_macros::*;
+
+use crate::{
+    api::{error::AssetError, user::UserUsage},
+    auth::{roles::Permission, user::get_logged_in_with_permission},
+    database::{
+        audit::{record_action, AuditAction, AuditTarget},
+        file::count_files_by_owner,
+        users::{get_usage, get_user_info, set_byte_limit as be_set_byte_limit},
+    },
+};
+
+/// Sets the quota of a user in bytes, None (or 0) moves them back to the default tier
+#[update]
+#[candid_method(update)]
+pub fn set_byte_limit(
+    principal: Principal,
+    byte_limit: Option<u64>,
+) -> Result<UserUsage, AssetError> {
+    match get_logged_in_with_permission(Permission::ManageUsers) {
+        Ok(admin) => match be_set_byte_limit(principal, byte_limit) {
+            Ok(_) => {
+                record_action(
+                    admin,
+                    AuditAction::SetByteLimit,
+                    AuditTarget::User(principal),
+                    None,
+                );
+                Ok(user_usage(principal))
+            }
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_user_usage(principal: Principal) -> Result<UserUsage, AssetError> {
+    match get_logged_in_with_permission(Permission::ViewUsers) {
+        Ok(_) => Ok(user_usage(principal)),
+        Err(e) => Err(e),
+    }
+}
+
+fn user_usage(principal: Principal) -> UserUsage {
+    let (bytes_used, byte_limit) = get_usage(principal);
+
+    UserUsage {
+        principal,
+        bytes_used,
+        byte_limit,
+        default_tier: get_user_info(principal).map_or(true, |user_info| user_info.byte_limit == 0),
+        number_of_files: count_files_by_owner(principal),
+    }
+}
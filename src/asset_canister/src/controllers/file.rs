@@ -1,38 +1,84 @@
-use crate::api::file::FEFile;
+use crate::api::error::AssetError;
+use crate::api::file::{FEFile, FEFileVersion, FileFilter, FilePage};
 use crate::auth::file::{
-    caller_accepted, caller_owns_file_or_is_superuser, chunk_size_okay, chunks_within_file_size,
-    file_size_accepted,
+    caller_accepted, caller_can_find_file, caller_can_manage_file, caller_can_read_file,
+    caller_owns_file_or_is_superuser, chunk_size_okay, file_size_accepted, metadata_accepted,
+    quota_accepted,
 };
 use crate::auth::ratelimit::{rate_limit, RateLimitMessageType};
+use crate::auth::share_link::create_share_link as be_create_share_link;
+use crate::auth::user::get_logged_in_principal;
 
+use crate::database::audit::{record_action, AuditAction, AuditTarget};
 use crate::database::chunks::{
-    get_chunk_by_id as be_get_chunk_by_id, put_chunk as be_put_chunk, ChunkID,
+    get_chunk_by_id as be_get_chunk_by_id, get_chunk_file_ids,
+    get_chunk_in_file as be_get_chunk_in_file, get_content_size, put_chunk as be_put_chunk,
+    ChunkID,
 };
+use crate::database::config::get_config;
 use crate::database::file::{
-    create_file as be_create_file, delete_file as be_delete_file,
-    get_current_file_id as be_get_current_file_id, get_file_by_id as be_get_file_by_id, FileID,
+    begin_replacement as be_begin_replacement, begin_upload as be_begin_upload,
+    commit_upload as be_commit_upload, create_file as be_create_file,
+    delete_file as be_delete_file, get_committed_file_by_id as be_get_committed_file_by_id,
+    get_current_file_id as be_get_current_file_id, get_file as be_get_file,
+    get_trash_by_owner as be_get_trash_by_owner, grant_access as be_grant_access,
+    list_files as be_list_files, restore_file as be_restore_file,
+    revoke_access as be_revoke_access, rotate_share_salt as be_rotate_share_salt,
+    set_visibility as be_set_visibility, update_file_metadata as be_update_file_metadata, FileID,
 };
-use crate::models::file::FileChunk;
+use crate::database::search::search_files as be_search_files;
+use crate::database::versions::{get_version as be_get_version, get_versions as be_get_versions};
+use crate::models::file::{File, FileChunk, FileType, Hash, Visibility};
+use crate::models::metadata::FileMetadata;
+use candid::candid_method;
+use candid::Principal;
 use ic_cdk_macros::*;
 use serde_bytes::ByteBuf;
 
 #[update]
+#[candid_method(update)]
 pub fn create_file(
     first_chunk: ByteBuf,
     file_name: String,
     number_of_chunks: u64,
     file_type: String,
-) -> Result<FEFile, String> {
+) -> Result<FEFile, AssetError> {
     match caller_accepted(RateLimitMessageType::CreateFile) {
         Ok(principal) => match file_size_accepted(number_of_chunks) {
-            Ok(_) => match chunk_size_okay(first_chunk.len() as usize) {
-                Ok(_) => match be_create_file(
-                    first_chunk,
-                    file_name,
-                    number_of_chunks,
-                    file_type,
-                    principal,
-                ) {
+            Ok(_) => match quota_accepted(principal, number_of_chunks) {
+                Ok(_) => match chunk_size_okay(first_chunk.len(), get_config().chunk_size) {
+                    Ok(_) => match be_create_file(
+                        first_chunk,
+                        file_name,
+                        number_of_chunks,
+                        file_type,
+                        principal,
+                    ) {
+                        Ok(file) => Ok(file.create_fe_type()),
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+
+        Err(e) => Err(e),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+pub fn begin_upload(
+    file_name: String,
+    number_of_chunks: u64,
+    file_type: String,
+) -> Result<FEFile, AssetError> {
+    match caller_accepted(RateLimitMessageType::CreateFile) {
+        Ok(principal) => match file_size_accepted(number_of_chunks) {
+            Ok(_) => match quota_accepted(principal, number_of_chunks) {
+                Ok(_) => match be_begin_upload(file_name, number_of_chunks, file_type, principal) {
                     Ok(file) => Ok(file.create_fe_type()),
                     Err(e) => Err(e),
                 },
@@ -40,17 +86,120 @@ pub fn create_file(
             },
             Err(e) => Err(e),
         },
+        Err(e) => Err(e),
+    }
+}
 
+#[update]
+#[candid_method(update)]
+pub fn commit_upload(file_id: FileID, expected_sha256: ByteBuf) -> Result<FEFile, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) => match Hash::try_from(expected_sha256.as_slice()) {
+                Ok(expected_sha256) => match be_commit_upload(&file, expected_sha256) {
+                    Ok(file) => Ok(file.create_fe_type()),
+                    Err(e) => Err(e),
+                },
+                Err(_) => Err(AssetError::InvalidHash),
+            },
+            Err(e) => Err(e),
+        },
         Err(e) => Err(e),
     }
 }
 
+/// Begins uploading new content for a file, chunks are put and committed against the returned upload
+/// Once committed it becomes the file's next version, served from the same URL
 #[update]
-pub fn delete_file(file_id: FileID) -> Result<String, String> {
+#[candid_method(update)]
+pub fn replace_file_content(
+    file_id: FileID,
+    number_of_chunks: u64,
+    file_type: String,
+) -> Result<FEFile, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) if !file.is_committed() => Err(AssetError::FileNotFound),
+            Ok(file) => match file_size_accepted(number_of_chunks) {
+                Ok(_) => match quota_accepted(file.owner, number_of_chunks) {
+                    Ok(_) => match be_begin_replacement(&file, number_of_chunks, file_type) {
+                        Ok(upload) => Ok(upload.create_fe_type()),
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Lists every kept version of the file, newest first
+#[query]
+#[candid_method(query)]
+pub fn list_versions(file_id: FileID) -> Result<Vec<FEFileVersion>, AssetError> {
+    match be_get_committed_file_by_id(&file_id) {
+        Ok(file) => match caller_can_read_file(&file) {
+            Ok(_) => {
+                let mut versions = vec![file.create_fe_version(get_content_size(&file))];
+                versions.extend(
+                    be_get_versions(file_id)
+                        .iter()
+                        .rev()
+                        .map(|version| version.create_fe_type(&file.url)),
+                );
+                Ok(versions)
+            }
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_version(file_id: FileID, version: u64) -> Result<FEFileVersion, AssetError> {
+    match be_get_committed_file_by_id(&file_id) {
+        Ok(file) => match caller_can_read_file(&file) {
+            Ok(_) if version == file.get_version() => {
+                Ok(file.create_fe_version(get_content_size(&file)))
+            }
+            Ok(_) => match be_get_version(file_id, version) {
+                Some(version) => Ok(version.create_fe_type(&file.url)),
+                None => Err(AssetError::VersionNotFound),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// The reason is recorded in the audit log when someone other than the owner deletes the file
+#[update]
+#[candid_method(update)]
+pub fn delete_file(file_id: FileID, reason: Option<String>) -> Result<String, AssetError> {
     match caller_accepted(RateLimitMessageType::DeleteFile) {
         Ok(principal) => match caller_owns_file_or_is_superuser(file_id) {
-            Ok(_) => match rate_limit(principal, RateLimitMessageType::DeleteFile) {
-                Ok(_) => be_delete_file(file_id),
+            Ok(file) => match rate_limit(principal, RateLimitMessageType::DeleteFile) {
+                Ok(_) => match be_delete_file(file_id) {
+                    Ok(message) => {
+                        if file.owner != principal {
+                            record_action(
+                                principal,
+                                AuditAction::DeleteFile,
+                                AuditTarget::File {
+                                    file_id,
+                                    owner: file.owner,
+                                },
+                                reason,
+                            );
+                        }
+                        Ok(message)
+                    }
+                    Err(e) => Err(e),
+                },
                 Err(e) => Err(e),
             },
 
@@ -60,16 +209,161 @@ pub fn delete_file(file_id: FileID) -> Result<String, String> {
     }
 }
 
+/// Takes a file back out of the trash before it is purged
 #[update]
-pub fn put_chunk(file_id: FileID, chunk: ByteBuf, order_id: u64) -> Result<FEFile, String> {
+#[candid_method(update)]
+pub fn restore_file(file_id: FileID) -> Result<FEFile, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(principal) => match be_get_file(&file_id) {
+            Some(file) => match caller_can_manage_file(&file) {
+                Ok(_) => match be_restore_file(&file) {
+                    Ok(restored_file) => {
+                        if file.owner != principal {
+                            record_action(
+                                principal,
+                                AuditAction::RestoreFile,
+                                AuditTarget::File {
+                                    file_id,
+                                    owner: file.owner,
+                                },
+                                None,
+                            );
+                        }
+                        Ok(restored_file.create_fe_type())
+                    }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            },
+            None => Err(AssetError::FileNotFound),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Lists the caller's deleted files, which can be restored until they are purged
+#[query]
+#[candid_method(query)]
+pub fn list_trash() -> Result<Vec<FEFile>, AssetError> {
+    match get_logged_in_principal() {
+        Ok(principal) => Ok(be_get_trash_by_owner(principal)
+            .iter()
+            .map(|file| file.create_fe_type())
+            .collect()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the file's metadata, and renames it if a file name is given
+#[update]
+#[candid_method(update)]
+pub fn update_file_metadata(
+    file_id: FileID,
+    file_name: Option<String>,
+    metadata: FileMetadata,
+) -> Result<FEFile, AssetError> {
     match caller_accepted(RateLimitMessageType::UpdateFile) {
         Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
-            Ok(file) => match chunks_within_file_size(&file) {
-                Ok(_) => match chunk_size_okay(chunk.len()) {
-                    Ok(_) => match be_put_chunk(&file, chunk, order_id) {
-                        Ok(_) => Ok(file.create_fe_type()),
-                        Err(e) => Err(e),
-                    },
+            Ok(file) => match metadata_accepted(&file_name, &metadata) {
+                Ok(_) => match be_update_file_metadata(&file, file_name, metadata) {
+                    Ok(file) => Ok(file.create_fe_type()),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+pub fn set_visibility(file_id: FileID, visibility: Visibility) -> Result<FEFile, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) => match be_set_visibility(&file, visibility) {
+                Ok(file) => Ok(file.create_fe_type()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Lets a principal fetch a private file, returns everyone who can
+#[update]
+#[candid_method(update)]
+pub fn grant_access(file_id: FileID, principal: Principal) -> Result<Vec<Principal>, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) => match be_grant_access(&file, principal) {
+                Ok(file) => Ok(file.accessors.into_iter().collect()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Stops a principal fetching a private file, returns everyone who still can
+#[update]
+#[candid_method(update)]
+pub fn revoke_access(file_id: FileID, principal: Principal) -> Result<Vec<Principal>, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) => match be_revoke_access(&file, principal) {
+                Ok(file) => Ok(file.accessors.into_iter().collect()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns a path that serves the file over HTTP, even if it is private, for `expires_in` seconds
+#[update]
+#[candid_method(update)]
+pub async fn create_share_link(file_id: FileID, expires_in: u64) -> Result<String, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) => match file.is_committed() {
+                true => be_create_share_link(&file, expires_in).await,
+                false => Err(AssetError::FileNotFound),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Stops every share link created for the file so far from working
+#[update]
+#[candid_method(update)]
+pub fn revoke_share_links(file_id: FileID) -> Result<FEFile, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) => match be_rotate_share_salt(&file) {
+                Ok(file) => Ok(file.create_fe_type()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+pub fn put_chunk(file_id: FileID, chunk: ByteBuf, order_id: u64) -> Result<FEFile, AssetError> {
+    match caller_accepted(RateLimitMessageType::UpdateFile) {
+        Ok(_) => match caller_owns_file_or_is_superuser(file_id) {
+            Ok(file) => match chunk_size_okay(chunk.len(), file.get_chunk_size()) {
+                Ok(_) => match be_put_chunk(&file, chunk, order_id) {
+                    Ok(file) => Ok(file.create_fe_type()),
                     Err(e) => Err(e),
                 },
                 Err(e) => Err(e),
@@ -80,20 +374,102 @@ pub fn put_chunk(file_id: FileID, chunk: ByteBuf, order_id: u64) -> Result<FEFil
     }
 }
 
+/// A chunk can be shared by several files, the caller has to be able to read one of them
+/// The chunk is returned as part of that file, so it doesn't give away the other files using it
 #[query]
-pub fn get_chunk_by_id(chunk_id: ChunkID) -> Result<FileChunk, String> {
-    be_get_chunk_by_id(chunk_id)
+#[candid_method(query)]
+pub fn get_chunk_by_id(chunk_id: ChunkID) -> Result<FileChunk, AssetError> {
+    match be_get_chunk_by_id(chunk_id) {
+        Ok(chunk) => {
+            let mut file_ids = get_chunk_file_ids(chunk_id);
+            if file_ids.is_empty() {
+                file_ids.push(chunk.file_id);
+            }
+
+            let results: Vec<Result<File, AssetError>> = file_ids
+                .iter()
+                .map(|file_id| match be_get_committed_file_by_id(file_id) {
+                    Ok(file) => caller_can_read_file(&file).map(|_| file),
+                    Err(e) => Err(e),
+                })
+                .collect();
+            match results.iter().find_map(|result| result.as_ref().ok()) {
+                Some(file) => be_get_chunk_in_file(file, chunk_id).ok_or(AssetError::ChunkNotFound),
+                None => Err(results
+                    .into_iter()
+                    .find_map(|result| result.err())
+                    .unwrap_or(AssetError::FileNotFound)),
+            }
+        }
+        Err(e) => Err(e),
+    }
 }
 
 #[query]
-pub fn get_current_file_id() -> Result<u64, String> {
+#[candid_method(query)]
+pub fn get_current_file_id() -> Result<u64, AssetError> {
     Ok(be_get_current_file_id())
 }
 
 #[query]
-pub fn get_file_by_id(file_id: FileID) -> Result<FEFile, String> {
-    match be_get_file_by_id(&file_id) {
-        Ok(file) => Ok(file.create_fe_type()),
+#[candid_method(query)]
+pub fn get_file_by_id(file_id: FileID) -> Result<FEFile, AssetError> {
+    match be_get_committed_file_by_id(&file_id) {
+        Ok(file) => match caller_can_read_file(&file) {
+            Ok(_) => Ok(file.create_fe_type()),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+pub fn list_my_files(cursor: Option<ByteBuf>, limit: u64) -> Result<FilePage, AssetError> {
+    match get_logged_in_principal() {
+        Ok(principal) => {
+            let filter = FileFilter {
+                file_type: None,
+                owner: Some(principal),
+                created_after: None,
+                created_before: None,
+            };
+
+            match be_list_files(&filter, cursor.map(|c| c.into_vec()), limit as usize) {
+                Ok((files, next_cursor)) => Ok(FilePage {
+                    files: files.iter().map(|file| file.create_fe_type()).collect(),
+                    next_cursor: next_cursor.map(ByteBuf::from),
+                }),
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Finds files with a word in their name or tags starting with each word of the query
+/// Anonymous callers only find public files
+#[query]
+#[candid_method(query)]
+pub fn search_files(
+    query: String,
+    owner: Option<Principal>,
+    file_type: Option<FileType>,
+    cursor: Option<ByteBuf>,
+    limit: u64,
+) -> Result<FilePage, AssetError> {
+    match be_search_files(
+        &query,
+        owner,
+        file_type,
+        caller_can_find_file,
+        cursor.map(|c| c.into_vec()),
+        limit as usize,
+    ) {
+        Ok((files, next_cursor)) => Ok(FilePage {
+            files: files.iter().map(|file| file.create_fe_type()).collect(),
+            next_cursor: next_cursor.map(ByteBuf::from),
+        }),
         Err(e) => Err(e),
     }
 }
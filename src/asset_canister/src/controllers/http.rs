@@ -1,7 +1,10 @@
+use crate::auth::share_link::{parse_share_link, verify_share_link, ShareLink};
+use crate::database::certification::{certificate_header, file_route};
 use crate::database::chunks::get_chunk_by_order_id_for_file;
-use crate::database::file::{get_file, FileID};
-use crate::models::file::FileType;
-use candid::{CandidType, Func, Nat};
+use crate::database::file::{get_committed_file_by_id, get_file, FileID};
+use crate::database::versions::get_version;
+use crate::models::file::{File, FileType, Hash};
+use candid::{candid_method, CandidType, Func, Nat};
 use ic_cdk_macros::{self, query};
 use num_traits::cast::ToPrimitive;
 use serde::Deserialize;
@@ -9,40 +12,75 @@ use serde_bytes::ByteBuf;
 use std::borrow::Cow;
 use std::str::FromStr;
 
+// Versioned URLs always serve the same content
 const CACHE_HEADER_VALUE: &str = "public, max-age=100000000, immutable";
+// The content of a file can be replaced, so its plain URL is only cached briefly
+const LATEST_CACHE_HEADER_VALUE: &str = "public, max-age=300";
+// Files reached through a share link must not outlive the link in a shared cache
+const SHARE_LINK_CACHE_HEADER_VALUE: &str = "private, no-store";
 
 #[query]
+#[candid_method(query)]
 fn http_request(request: HttpRequest) -> HttpResponse {
     match extract_route(&request.url) {
-        Route::File(file_id) => start_streaming_file(file_id),
+        Route::File(file_id, share_link, version) => {
+            match request.header("Range").and_then(|r| parse_range(r)) {
+                Some(range) => serve_file_range(file_id, share_link, version, range),
+                None => start_streaming_file(file_id, share_link, version),
+            }
+        }
+        Route::Deleted => HttpResponse::gone(),
+        Route::InvalidShareLink => HttpResponse::forbidden(),
         _ => HttpResponse::not_found(),
     }
 }
 
-fn start_streaming_file(file_id: FileID) -> HttpResponse {
-    if let Some(file) = get_file(&file_id) {
-        let file_type = file.file_type.clone();
-        let number_of_chunks = file.number_of_chunks.clone();
+fn start_streaming_file(
+    file_id: FileID,
+    share_link: Option<ShareLink>,
+    version: Option<u64>,
+) -> HttpResponse {
+    if let Some(file) = get_servable_file(file_id, &share_link, version) {
+        let file_type = file.file_type;
+        let number_of_chunks = file.number_of_chunks;
         if let Some(chunk) = get_chunk_by_order_id_for_file(&file, 0) {
             let streaming_strategy = if number_of_chunks > 1 {
-                Some(StreamingStrategy::Callback {
-                    callback: Func {
-                        principal: ic_cdk::id(),
-                        method: "http_request_streaming_callback".to_string(),
-                    },
-                    token: build_token(file_type, file_id, 1),
+                get_chunk_by_order_id_for_file(&file, 1).map(|next_chunk| {
+                    StreamingStrategy::Callback {
+                        callback: Func {
+                            principal: ic_cdk::id(),
+                            method: "http_request_streaming_callback".to_string(),
+                        },
+                        token: build_token(
+                            file_type,
+                            file_id,
+                            &share_link,
+                            version,
+                            1,
+                            next_chunk.sha256,
+                        ),
+                    }
                 })
             } else {
                 None
             };
 
+            let mut headers = vec![
+                HeaderField("Content-Type".to_string(), String::from(file_type.as_str())),
+                HeaderField(
+                    "Cache-Control".to_string(),
+                    cache_header_value(&share_link, version).to_string(),
+                ),
+                HeaderField("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+                HeaderField("Accept-Ranges".to_string(), "bytes".to_string()),
+            ];
+            if let Some((name, value)) = file_certificate_header(&file, version) {
+                headers.push(HeaderField(name, value));
+            }
+
             let response = HttpResponse {
                 status_code: 200,
-                headers: vec![
-                    HeaderField("Content-Type".to_string(), String::from(file_type.as_str())),
-                    HeaderField("Cache-Control".to_string(), CACHE_HEADER_VALUE.to_string()),
-                    HeaderField("Access-Control-Allow-Origin".to_string(), "*".to_string()),
-                ],
+                headers,
                 body: Cow::Owned(chunk.chunk_data),
                 streaming_strategy,
             };
@@ -54,21 +92,152 @@ fn start_streaming_file(file_id: FileID) -> HttpResponse {
     HttpResponse::not_found()
 }
 
+/// Serves a single byte range of a file as a `206 Partial Content` response
+///
+/// Every chunk except the last one is expected to hold exactly the file's chunk size in bytes,
+/// so a byte offset maps directly onto a chunk. A response never spans more than one chunk,
+/// if the requested range is longer the client receives the remainder of the chunk and
+/// requests the rest of the range again (which browsers do when seeking through video)
+/// The certificate covers the whole file rather than a range of it, so none is sent
+fn serve_file_range(
+    file_id: FileID,
+    share_link: Option<ShareLink>,
+    version: Option<u64>,
+    range: ByteRange,
+) -> HttpResponse {
+    if let Some(file) = get_servable_file(file_id, &share_link, version) {
+        if let Some(total_size) = get_file_size(&file) {
+            let (start, end) = match range.resolve(total_size) {
+                Some(bounds) => bounds,
+                None => return HttpResponse::range_not_satisfiable(total_size),
+            };
+
+            let chunk_size = file.get_chunk_size();
+            let chunk_index = start / chunk_size;
+            let chunk_start = chunk_index * chunk_size;
+
+            if let Some(chunk) = get_chunk_by_order_id_for_file(&file, chunk_index) {
+                let chunk_length = chunk.chunk_data.len() as u64;
+                // The chunk is shorter than expected, so offsets can't be mapped onto chunks
+                if start - chunk_start >= chunk_length {
+                    return start_streaming_file(file_id, share_link, version);
+                }
+                let end = end.min(chunk_start + chunk_length - 1);
+                let body = chunk.chunk_data
+                    [(start - chunk_start) as usize..=(end - chunk_start) as usize]
+                    .to_vec();
+
+                let headers = vec![
+                    HeaderField(
+                        "Content-Type".to_string(),
+                        String::from(file.file_type.as_str()),
+                    ),
+                    HeaderField(
+                        "Cache-Control".to_string(),
+                        cache_header_value(&share_link, version).to_string(),
+                    ),
+                    HeaderField("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+                    HeaderField("Accept-Ranges".to_string(), "bytes".to_string()),
+                    HeaderField(
+                        "Content-Range".to_string(),
+                        format!("bytes {}-{}/{}", start, end, total_size),
+                    ),
+                    HeaderField("Content-Length".to_string(), body.len().to_string()),
+                ];
+
+                return HttpResponse {
+                    status_code: 206,
+                    headers,
+                    body: Cow::Owned(ByteBuf::from(body)),
+                    streaming_strategy: None,
+                };
+            }
+        }
+    }
+
+    HttpResponse::not_found()
+}
+
+/// There is no caller to check over HTTP, so private files are only served through a share link
+/// which `extract_route` has already verified
+/// An earlier version is returned as the file was at that version
+fn get_servable_file(
+    file_id: FileID,
+    share_link: &Option<ShareLink>,
+    version: Option<u64>,
+) -> Option<File> {
+    let file = get_committed_file_by_id(&file_id)
+        .ok()
+        .filter(|file| !file.is_private() || share_link.is_some())?;
+
+    match version {
+        Some(version) if version != file.get_version() => {
+            get_version(file_id, version).map(|version| file.at_version(&version))
+        }
+        _ => Some(file),
+    }
+}
+
+fn cache_header_value(share_link: &Option<ShareLink>, version: Option<u64>) -> &'static str {
+    match (share_link, version) {
+        (Some(_), _) => SHARE_LINK_CACHE_HEADER_VALUE,
+        (None, Some(_)) => CACHE_HEADER_VALUE,
+        (None, None) => LATEST_CACHE_HEADER_VALUE,
+    }
+}
+
+/// Only the current content of each file is certified, so versioned URLs go without a certificate
+fn file_certificate_header(file: &File, version: Option<u64>) -> Option<(String, String)> {
+    match version {
+        Some(_) => None,
+        None => certificate_header(&file_route(file)),
+    }
+}
+
+/// Total size of the file in bytes, worked out from the size of the last chunk
+/// Returns None if the last chunk hasn't been uploaded yet
+fn get_file_size(file: &File) -> Option<u64> {
+    let last_order_id = file.number_of_chunks.checked_sub(1)?;
+    get_chunk_by_order_id_for_file(file, last_order_id)
+        .map(|chunk| last_order_id * file.get_chunk_size() + chunk.chunk_data.len() as u64)
+}
+
 #[query]
+#[candid_method(query)]
 fn http_request_streaming_callback(token: Token) -> StreamingCallbackHttpResponse {
     continue_streaming_file(token)
 }
 
 fn continue_streaming_file(token: Token) -> StreamingCallbackHttpResponse {
-    if let Route::File(file_id) = extract_route(&token.key) {
+    if let Route::File(file_id, share_link, version) = extract_route(&token.key) {
         let chunk_index = token.index.0.to_u64().unwrap();
 
-        if let Some(file) = get_file(&file_id) {
-            let file_type = file.file_type.clone();
-            let number_of_chunks = file.number_of_chunks.clone();
+        if let Some(file) = get_servable_file(file_id, &share_link, version) {
+            let file_type = file.file_type;
+            let number_of_chunks = file.number_of_chunks;
             if let Some(chunk) = get_chunk_by_order_id_for_file(&file, chunk_index) {
-                let token = if (chunk_index as u64) <= number_of_chunks {
-                    Some(build_token(file_type, file_id, chunk_index + 1))
+                // Stop streaming if the chunk has changed since the token was issued
+                if token.sha256.is_some()
+                    && token.sha256.as_ref().map(|hash| &hash[..])
+                        != chunk.sha256.as_ref().map(|hash| &hash[..])
+                {
+                    return StreamingCallbackHttpResponse {
+                        body: ByteBuf::new(),
+                        token: None,
+                    };
+                }
+
+                let token = if chunk_index + 1 < number_of_chunks {
+                    get_chunk_by_order_id_for_file(&file, chunk_index + 1).map(|next_chunk| {
+                        build_token(
+                            file_type,
+                            file_id,
+                            &share_link,
+                            version,
+                            chunk_index + 1,
+                            next_chunk.sha256,
+                        )
+                    })
                 } else {
                     None
                 };
@@ -86,7 +255,11 @@ fn continue_streaming_file(token: Token) -> StreamingCallbackHttpResponse {
     }
 }
 
-pub fn extract_route(path: &str) -> Route {
+/// Share links carry `?token=...&exp=...`, which are checked here against the file's current salt
+/// Files in the trash are routed to a `410 Gone` whether or not they were shared
+/// `?v=3` asks for a particular version of the file
+pub fn extract_route(url: &str) -> Route {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
     let path = path
         .trim_start_matches('/')
         .trim_end_matches('/')
@@ -100,7 +273,20 @@ pub fn extract_route(path: &str) -> Route {
     match parts[0] {
         "video" | "image" if parts.len() > 1 => {
             if let Ok(file_id) = FileID::from_str(parts[1]) {
-                Route::File(file_id)
+                let file = get_file(&file_id);
+                let version = parse_version(query);
+                match parse_share_link(query) {
+                    _ if file.as_ref().is_some_and(|file| file.is_deleted()) => Route::Deleted,
+                    Some(share_link) => match file {
+                        Some(file)
+                            if verify_share_link(file_id, file.get_share_salt(), &share_link) =>
+                        {
+                            Route::File(file_id, Some(share_link), version)
+                        }
+                        _ => Route::InvalidShareLink,
+                    },
+                    None => Route::File(file_id, None, version),
+                }
             } else {
                 Route::Other
             }
@@ -110,20 +296,106 @@ pub fn extract_route(path: &str) -> Route {
     }
 }
 
-fn build_token(file_type: FileType, blob_id: u64, index: u64) -> Token {
+/// Parses a `Range` header value such as `bytes=0-499`, `bytes=500-` or `bytes=-500`
+///
+/// Only a single range is supported, multiple ranges or malformed values return None,
+/// in which case the header is ignored and the whole file is served
+pub fn parse_range(value: &str) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        return Some(ByteRange::Suffix(u64::from_str(end).ok()?));
+    }
+
+    let start = u64::from_str(start).ok()?;
+    if end.is_empty() {
+        return Some(ByteRange::From(start, None));
+    }
+
+    let end = u64::from_str(end).ok()?;
+    if end < start {
+        return None;
+    }
+    Some(ByteRange::From(start, Some(end)))
+}
+
+/// Reads `v` out of a query string, a malformed version is ignored
+fn parse_version(query: &str) -> Option<u64> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "v")
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+}
+
+/// The key keeps the share link and version, so each later chunk is checked against them again
+fn build_token(
+    file_type: FileType,
+    blob_id: u64,
+    share_link: &Option<ShareLink>,
+    version: Option<u64>,
+    index: u64,
+    sha256: Option<Hash>,
+) -> Token {
+    let mut query: Vec<String> = vec![];
+    if let Some(share_link) = share_link {
+        query.push(share_link.query_string());
+    }
+    if let Some(version) = version {
+        query.push(format!("v={}", version));
+    }
+
+    let key = match query.is_empty() {
+        true => format!("{}/{}", file_type.url_slug(), blob_id),
+        false => format!("{}/{}?{}", file_type.url_slug(), blob_id, query.join("&")),
+    };
+
     Token {
-        key: format!("{}/{}", file_type.url_slug(), blob_id),
+        key,
         content_encoding: String::default(),
         index: index.into(),
-        sha256: None,
+        sha256: sha256.map(|hash| ByteBuf::from(hash.to_vec())),
     }
 }
 
 pub enum Route {
-    File(u64),
+    // Holds the share link the file was reached through, once it has been verified,
+    // and the version asked for
+    File(u64, Option<ShareLink>, Option<u64>),
+    Deleted,
+    InvalidShareLink,
     Other,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange {
+    /// `bytes=start-end`, or `bytes=start-` when there is no end
+    From(u64, Option<u64>),
+    /// `bytes=-length`, the last `length` bytes of the file
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Returns the inclusive `(start, end)` byte positions of the range within a file of `total_size` bytes
+    /// or None if the range can't be satisfied
+    pub fn resolve(&self, total_size: u64) -> Option<(u64, u64)> {
+        let last_byte = total_size.checked_sub(1)?;
+        match *self {
+            ByteRange::From(start, end) => match start <= last_byte {
+                true => Some((start, end.unwrap_or(last_byte).min(last_byte))),
+                false => None,
+            },
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(length) => Some((total_size.saturating_sub(length), last_byte)),
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct HeaderField(pub String, pub String);
 
@@ -164,7 +436,7 @@ pub struct StreamingCallbackHttpResponse {
 }
 
 impl HttpRequest {
-    pub fn _header(&self, key: &str) -> Option<&String> {
+    pub fn header(&self, key: &str) -> Option<&String> {
         let key_lower = key.to_lowercase();
         self.headers
             .iter()
@@ -183,14 +455,30 @@ impl HttpResponse {
         }
     }
 
-    pub fn _gone() -> HttpResponse {
+    pub fn gone() -> HttpResponse {
         HttpResponse::status_code(410)
     }
 
+    pub fn forbidden() -> HttpResponse {
+        HttpResponse::status_code(403)
+    }
+
     pub fn not_found() -> HttpResponse {
         HttpResponse::status_code(404)
     }
 
+    pub fn range_not_satisfiable(total_size: u64) -> HttpResponse {
+        HttpResponse {
+            status_code: 416,
+            headers: vec![HeaderField(
+                "Content-Range".to_owned(),
+                format!("bytes */{}", total_size),
+            )],
+            body: Cow::default(),
+            streaming_strategy: None,
+        }
+    }
+
     pub fn _moved_permanently(location: &str) -> HttpResponse {
         HttpResponse::_moved(301, location, None)
     }
@@ -215,3 +503,53 @@ impl HttpResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_closed_open_and_suffix_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-99"),
+            Some(ByteRange::From(0, Some(99)))
+        );
+        assert_eq!(
+            parse_range(" bytes= 5 - 5 "),
+            Some(ByteRange::From(5, Some(5)))
+        );
+        assert_eq!(parse_range("bytes=100-"), Some(ByteRange::From(100, None)));
+        assert_eq!(parse_range("bytes=-500"), Some(ByteRange::Suffix(500)));
+    }
+
+    #[test]
+    fn rejects_malformed_and_multiple_ranges() {
+        assert_eq!(parse_range("bytes=0-99,200-299"), None);
+        assert_eq!(parse_range("bytes=99-0"), None);
+        assert_eq!(parse_range("items=0-99"), None);
+        assert_eq!(parse_range("bytes=0"), None);
+        assert_eq!(parse_range("bytes=-"), None);
+        assert_eq!(parse_range("bytes=a-b"), None);
+    }
+
+    #[test]
+    fn resolves_ranges_within_the_file() {
+        assert_eq!(ByteRange::From(0, Some(99)).resolve(1000), Some((0, 99)));
+        assert_eq!(
+            ByteRange::From(900, Some(2000)).resolve(1000),
+            Some((900, 999))
+        );
+        assert_eq!(ByteRange::From(100, None).resolve(1000), Some((100, 999)));
+        assert_eq!(ByteRange::Suffix(100).resolve(1000), Some((900, 999)));
+        assert_eq!(ByteRange::Suffix(5000).resolve(1000), Some((0, 999)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_resolve_to_none() {
+        assert_eq!(ByteRange::From(1000, None).resolve(1000), None);
+        assert_eq!(ByteRange::From(1000, Some(1001)).resolve(1000), None);
+        assert_eq!(ByteRange::Suffix(0).resolve(1000), None);
+        assert_eq!(ByteRange::From(0, None).resolve(0), None);
+        assert_eq!(ByteRange::Suffix(10).resolve(0), None);
+    }
+}
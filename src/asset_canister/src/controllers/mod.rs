@@ -1,3 +1,4 @@
 pub mod admin;
 pub mod file;
 pub mod http;
+pub mod user;
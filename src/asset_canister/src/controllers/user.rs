@@ -0,0 +1,68 @@
+use candid::candid_method;
+use ic_cdk_macros::*;
+
+use crate::api::error::AssetError;
+use crate::api::user::Account;
+use crate::auth::appeals::{submit_appeal as be_submit_appeal, Appeal};
+use crate::auth::moderation::is_blocked;
+use crate::auth::ratelimit::{
+    get_daily_upload_allowance, get_rate_limited_until, rate_limit, RateLimitMessageType,
+};
+use crate::auth::user::get_logged_in_principal;
+use crate::database::file::count_files_by_owner;
+use crate::database::users::get_usage;
+use crate::jobs::deletion::{get_deletion_job, start_deletion_job, DeletionJob};
+
+#[query]
+#[candid_method(query)]
+pub fn get_my_account() -> Result<Account, AssetError> {
+    match get_logged_in_principal() {
+        Ok(principal) => {
+            let (bytes_used, byte_limit) = get_usage(principal);
+            let (uploads_remaining_today, next_upload_reset) =
+                get_daily_upload_allowance(principal);
+
+            Ok(Account {
+                principal,
+                blocked: is_blocked(principal),
+                bytes_used,
+                byte_limit,
+                number_of_files: count_files_by_owner(principal),
+                uploads_remaining_today,
+                next_upload_reset,
+                rate_limited_until: get_rate_limited_until(principal),
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes all of the caller's files in batches, progress can be followed with the returned job
+/// The account itself is kept so that quotas and blocks still apply
+#[update]
+#[candid_method(update)]
+pub fn delete_my_account() -> Result<DeletionJob, AssetError> {
+    match get_logged_in_principal() {
+        Ok(principal) => {
+            start_deletion_job(principal);
+            match get_deletion_job(principal) {
+                Some(job) => Ok(job),
+                None => ic_cdk::trap("Deletion job was not started"),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Asks the moderators to unblock the caller, who must be blocked
+#[update]
+#[candid_method(update)]
+pub fn submit_appeal(text: String) -> Result<Appeal, AssetError> {
+    match get_logged_in_principal() {
+        Ok(principal) => match rate_limit(principal, RateLimitMessageType::UpdateFile) {
+            Ok(_) => be_submit_appeal(principal, text),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,95 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::log::Log as StableLog;
+use std::cell::RefCell;
+
+use super::file::FileID;
+use super::memory::{get_memory, Memory, AUDIT_LOG_DATA_MEMORY_ID, AUDIT_LOG_INDEX_MEMORY_ID};
+
+/// Reasons are cut off at 500 characters
+const MAX_REASON_LENGTH: usize = 500;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum AuditAction {
+    BlockUser,
+    UnblockUser,
+    BlockAndDeleteUser,
+    DeleteFile,
+    RestoreFile,
+    PruneFile,
+    AcceptAppeal,
+    RejectAppeal,
+    SetRole,
+    SetByteLimit,
+    SetConfig,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum AuditTarget {
+    User(Principal),
+    File { file_id: FileID, owner: Principal },
+    Canister,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct AuditEntry {
+    // The position of the entry in the log
+    pub id: u64,
+    pub time: u64,
+    pub actor: Principal,
+    pub action: AuditAction,
+    pub target: AuditTarget,
+    pub reason: Option<String>,
+}
+
+thread_local! {
+    // Append-only, entries are never changed or removed
+    static AUDIT_LOG: RefCell<StableLog<Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            get_memory(AUDIT_LOG_INDEX_MEMORY_ID),
+            get_memory(AUDIT_LOG_DATA_MEMORY_ID)
+        )
+        .expect("Failed to initialise the audit log")
+    );
+}
+
+/// Appends an admin or moderation action to the audit log
+pub fn record_action(
+    actor: Principal,
+    action: AuditAction,
+    target: AuditTarget,
+    reason: Option<String>,
+) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let entry = AuditEntry {
+            id: log.len() as u64,
+            time: time(),
+            actor,
+            action,
+            target,
+            reason: reason.map(|reason| reason.chars().take(MAX_REASON_LENGTH).collect()),
+        };
+        // The audit log must not fail the action, which has already happened
+        let _ = log.append(&Encode!(&entry).unwrap());
+    });
+}
+
+/// Gets entries newest first, starting before the entry with the ID `before` if it is given
+/// Returns the entries and the `before` to pass to get the next page, if there is one
+pub fn get_audit_log(before: Option<u64>, limit: usize) -> (Vec<AuditEntry>, Option<u64>) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = before.map_or(log.len(), |before| (before as usize).min(log.len()));
+        let start = end.saturating_sub(limit.clamp(1, MAX_PAGE_SIZE));
+
+        let entries = (start..end)
+            .rev()
+            .filter_map(|index| log.get(index))
+            .filter_map(|bytes| Decode!(&bytes, AuditEntry).ok())
+            .collect();
+
+        (entries, (start > 0).then_some(start as u64))
+    })
+}
@@ -0,0 +1,98 @@
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, RbTree};
+use serde::Serialize;
+use serde_cbor::ser::Serializer;
+use std::cell::RefCell;
+
+use crate::models::file::{File, Hash};
+
+use super::chunks::hash_file_content;
+use super::file::{for_each_file, insert_file};
+
+/// The label HTTP gateways look up asset hashes under
+const LABEL_ASSETS: &[u8] = b"http_assets";
+
+thread_local! {
+    // Maps the route of each file (e.g. `/image/1`) to the SHA-256 of its content
+    // This lives on the heap and is rebuilt from the stored files on init and upgrade
+    static ASSET_HASHES: RefCell<RbTree<String, Hash>> = RefCell::default();
+}
+
+/// The path a file is served from, which is the key it is certified under
+pub fn file_route(file: &File) -> String {
+    format!("/{}/{}", file.file_type.url_slug(), file.id)
+}
+
+/// Adds or updates the hash of a file in the certified tree
+/// Private files are included, since share links serve them over HTTP
+pub fn certify_file(file: &File) {
+    if let Some(sha256) = file.sha256 {
+        ASSET_HASHES.with(|tree| tree.borrow_mut().insert(file_route(file), sha256));
+        update_certified_data();
+    }
+}
+
+/// Removes a file from the certified tree
+pub fn uncertify_file(file: &File) {
+    ASSET_HASHES.with(|tree| tree.borrow_mut().delete(file_route(file).as_bytes()));
+    update_certified_data();
+}
+
+/// Rebuilds the certified tree from every stored file outside the trash
+/// Files saved before responses were certified are left out until `hash_legacy_file` has run on them
+pub fn certify_all_files() {
+    ASSET_HASHES.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for_each_file(|file| match file.sha256 {
+            Some(sha256) if file.is_committed() && !file.is_deleted() => {
+                tree.insert(file_route(file), sha256)
+            }
+            _ => (),
+        });
+    });
+
+    update_certified_data();
+}
+
+/// Works out and saves the SHA-256 of a file saved before responses were certified, then certifies it
+/// Files in the trash are given their hash too, so they are certified if restored
+pub fn hash_legacy_file(file: &File) {
+    let hashed_file = File {
+        sha256: Some(hash_file_content(file)),
+        ..file.clone()
+    };
+    let _ = insert_file(hashed_file.id, hashed_file.clone());
+    if !hashed_file.is_deleted() {
+        certify_file(&hashed_file);
+    }
+}
+
+/// Builds the `IC-Certificate` header for a file route
+/// Returns None outside of query calls, where no certificate is available
+pub fn certificate_header(route: &str) -> Option<(String, String)> {
+    let certificate = ic_cdk::api::data_certificate()?;
+
+    ASSET_HASHES.with(|tree| {
+        let tree = tree.borrow();
+        let witness = labeled(LABEL_ASSETS, tree.witness(route.as_bytes()));
+
+        let mut serializer = Serializer::new(vec![]);
+        serializer.self_describe().ok()?;
+        witness.serialize(&mut serializer).ok()?;
+
+        Some((
+            String::from("IC-Certificate"),
+            format!(
+                "certificate=:{}:, tree=:{}:",
+                base64::encode(certificate),
+                base64::encode(serializer.into_inner())
+            ),
+        ))
+    })
+}
+
+fn update_certified_data() {
+    ASSET_HASHES.with(|tree| {
+        let root_hash = labeled_hash(LABEL_ASSETS, &tree.borrow().root_hash());
+        ic_cdk::api::set_certified_data(&root_hash);
+    });
+}
@@ -1,15 +1,14 @@
 use candid::{Decode, Encode};
 use ic_cdk::api::time;
 use ic_stable_structures::btreemap::InsertError;
-use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::cell::Cell as StableCell;
+use ic_stable_structures::{StableBTreeMap, Storable};
 use serde_bytes::ByteBuf;
-use std::vec;
+use sha2::{Digest, Sha256};
 use std::{borrow::Cow, cell::RefCell};
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
-
-use crate::models::file::{hash_bytes, File, FileChunk};
+use crate::api::error::AssetError;
+use crate::models::file::{hash_bytes, sha256_bytes, File, FileChunk, Hash};
 
 const MAX_KEY_SIZE: u32 = 8;
 // This value can potentially break
@@ -21,12 +20,22 @@ const MAX_KEY_SIZE: u32 = 8;
 const MAX_VALUE_SIZE: u32 = 2000000;
 
 use super::file::{insert_file, FileID};
-use super::users::update_user_info_chunk;
+use super::memory::{
+    get_memory, Memory, CHUNKS_MEMORY_ID, CHUNK_HASH_INDEX_MEMORY_ID, CHUNK_REFERENCES_MEMORY_ID,
+    CURRENT_CHUNK_ID_MEMORY_ID,
+};
+use super::versions::get_versions;
+
+// Chunks are found by the SHA3-256 of their content
+const MAX_HASH_KEY_SIZE: u32 = 32;
+const MAX_CHUNK_ID_SIZE: u32 = 8;
+// Keys are the chunk ID followed by the file ID
+const MAX_REFERENCE_KEY_SIZE: u32 = 16;
 
 pub type ChunkID = u64;
 
 impl Storable for FileChunk {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -37,18 +46,57 @@ impl Storable for FileChunk {
 }
 
 thread_local! {
-    pub static CURRENT_CHUNK_ID: RefCell<ChunkID> = RefCell::default();
-
-    static CHUNK_MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static CURRENT_CHUNK_ID: RefCell<StableCell<ChunkID, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CURRENT_CHUNK_ID_MEMORY_ID), 0)
+            .expect("Failed to initialise the chunk ID counter")
+    );
 
     static CHUNK_MAP: RefCell<StableBTreeMap<Memory, ChunkID, FileChunk>> = RefCell::new(
         StableBTreeMap::init(
-            CHUNK_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+            get_memory(CHUNKS_MEMORY_ID),
             MAX_KEY_SIZE,
             MAX_VALUE_SIZE
         )
     );
+
+    // Identical chunks are stored once, this finds the chunk holding some content
+    // Chunks saved before chunks were shared aren't in the index
+    static CHUNK_HASH_INDEX: RefCell<StableBTreeMap<Memory, Vec<u8>, ChunkID>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(CHUNK_HASH_INDEX_MEMORY_ID),
+            MAX_HASH_KEY_SIZE,
+            MAX_CHUNK_ID_SIZE
+        )
+    );
+
+    // The files using each chunk, a chunk is removed once no file uses it
+    static CHUNK_REFERENCES: RefCell<StableBTreeMap<Memory, Vec<u8>, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(CHUNK_REFERENCES_MEMORY_ID),
+            MAX_REFERENCE_KEY_SIZE,
+            0
+        )
+    );
+}
+
+fn next_chunk_id() -> ChunkID {
+    CURRENT_CHUNK_ID.with(|current_id| {
+        let id = *current_id.borrow().get();
+        current_id
+            .borrow_mut()
+            .set(id + 1)
+            .expect("Failed to save the chunk ID counter");
+        id
+    })
+}
+
+pub fn set_current_chunk_id(id: ChunkID) {
+    CURRENT_CHUNK_ID.with(|current_id| {
+        current_id
+            .borrow_mut()
+            .set(id)
+            .expect("Failed to save the chunk ID counter")
+    });
 }
 
 fn get(key: ChunkID) -> Option<FileChunk> {
@@ -59,121 +107,346 @@ fn insert(key: ChunkID, value: FileChunk) -> Result<Option<FileChunk>, InsertErr
     CHUNK_MAP.with(|p| p.borrow_mut().insert(key, value))
 }
 
+/// Chunks are kept while their files are in the trash, this is for when the last one is purged
+/// A chunk can be shared, so it is never marked deleted itself
 pub fn remove_chunk(key: ChunkID) -> Option<FileChunk> {
-    // TODO: instead of removing this, let's set soft-delete to true instead
     CHUNK_MAP.with(|p| p.borrow_mut().remove(&key))
 }
 
+/// Stops the file using the chunk, and removes the chunk if no other file uses it
+/// Returns the number of bytes freed
+pub fn release_chunk(chunk_id: ChunkID, file_id: FileID) -> u64 {
+    CHUNK_REFERENCES.with(|p| p.borrow_mut().remove(&reference_key(chunk_id, file_id)));
+    if !get_chunk_file_ids(chunk_id).is_empty() {
+        return 0;
+    }
+
+    match remove_chunk(chunk_id) {
+        Some(chunk) => {
+            CHUNK_HASH_INDEX.with(|p| {
+                let mut index = p.borrow_mut();
+                // Only remove the index entry if it points at this chunk
+                if index.get(&chunk.hash.to_vec()) == Some(chunk_id) {
+                    index.remove(&chunk.hash.to_vec());
+                }
+            });
+            chunk.chunk_data.len() as u64
+        }
+        None => 0,
+    }
+}
+
+/// Hands the chunks of one file over to another, which keeps them stored
+pub fn move_chunk_references(chunk_ids: &[ChunkID], from: FileID, to: FileID) {
+    CHUNK_REFERENCES.with(|p| {
+        let mut references = p.borrow_mut();
+        chunk_ids.iter().for_each(|chunk_id| {
+            let _ = references.insert(reference_key(*chunk_id, to), ());
+            references.remove(&reference_key(*chunk_id, from));
+        });
+    });
+}
+
+/// The files using a chunk, empty for chunks saved before chunks were shared
+pub fn get_chunk_file_ids(chunk_id: ChunkID) -> Vec<FileID> {
+    CHUNK_REFERENCES.with(|p| {
+        p.borrow()
+            .range(chunk_id.to_be_bytes().to_vec(), None)
+            .map(|(key, _)| file_id_from_reference_key(&key))
+            .collect()
+    })
+}
+
+fn find_chunk_by_hash(hash: &Hash) -> Option<ChunkID> {
+    CHUNK_HASH_INDEX.with(|p| p.borrow().get(&hash.to_vec()))
+}
+
+fn insert_chunk_reference(chunk_id: ChunkID, file_id: FileID) {
+    let _ = CHUNK_REFERENCES.with(|p| p.borrow_mut().insert(reference_key(chunk_id, file_id), ()));
+}
+
+// IDs are big-endian so that the references of a chunk are next to each other
+fn reference_key(chunk_id: ChunkID, file_id: FileID) -> Vec<u8> {
+    let mut key = chunk_id.to_be_bytes().to_vec();
+    key.extend_from_slice(&file_id.to_be_bytes());
+    key
+}
+
+fn file_id_from_reference_key(key: &[u8]) -> FileID {
+    let mut file_id = [0; 8];
+    file_id.copy_from_slice(&key[8..16]);
+    FileID::from_be_bytes(file_id)
+}
+
+/// The order ID of each of the file's chunks, in the same order as `chunk_ids`
+pub fn get_chunk_order_ids(file: &File) -> Vec<u64> {
+    match &file.chunk_order_ids {
+        Some(chunk_order_ids) => chunk_order_ids.clone(),
+        None => file
+            .chunk_ids
+            .iter()
+            .map(|chunk_id| get(*chunk_id).map_or(u64::MAX, |chunk| chunk.order_id))
+            .collect(),
+    }
+}
+
+/// A chunk may be shared with other files, so its file and order ID are set to the file's own
+fn get_chunk_for_file(file: &File, chunk_id: ChunkID, order_id: u64) -> Option<FileChunk> {
+    get(chunk_id).map(|chunk| FileChunk {
+        file_id: file.id,
+        order_id,
+        ..chunk
+    })
+}
+
+/// Gets a chunk as part of the file, or of one of the file's earlier versions
+/// None if neither uses the chunk
+pub fn get_chunk_in_file(file: &File, chunk_id: ChunkID) -> Option<FileChunk> {
+    let find_order_id = |chunk_ids: &[ChunkID], order_ids: &[u64]| {
+        chunk_ids
+            .iter()
+            .position(|id| *id == chunk_id)
+            .and_then(|index| order_ids.get(index).copied())
+    };
+
+    let order_id = find_order_id(&file.chunk_ids, &get_chunk_order_ids(file)).or_else(|| {
+        get_versions(file.id)
+            .iter()
+            .find_map(|version| find_order_id(&version.chunk_ids, &version.chunk_order_ids))
+    })?;
+    get_chunk_for_file(file, chunk_id, order_id)
+}
+
 /// Inserts a chunk into the store and updates the file to include reference to a chunk
-pub fn put_chunk<'a>(file: &'a File, chunk: ByteBuf, order_id: u64) -> Result<&'a File, String> {
-    let bytes_used = chunk.len() as u64;
-    match insert_chunk(file.id, chunk, order_id) {
+pub fn put_chunk(file: &File, chunk: ByteBuf, order_id: u64) -> Result<File, AssetError> {
+    if file.is_committed() {
+        return Err(AssetError::UploadComplete);
+    }
+    if order_id >= file.number_of_chunks {
+        return Err(AssetError::ChunkOutOfRange);
+    }
+    if get_chunk_by_order_id_for_file(file, order_id).is_some() {
+        return Err(AssetError::ChunkAlreadyUploaded);
+    }
+
+    // Content that is already stored is shared rather than saved again
+    let hash = hash_bytes(&chunk);
+    let chunk_id = match find_chunk_by_hash(&hash) {
+        Some(chunk_id) => Ok(chunk_id),
+        None => insert_chunk(file.id, chunk, order_id, hash),
+    };
+
+    match chunk_id {
         Ok(chunk_id) => {
+            insert_chunk_reference(chunk_id, file.id);
+
             let mut chunk_ids = file.chunk_ids.clone();
+            let mut chunk_order_ids = get_chunk_order_ids(file);
 
             chunk_ids.push(chunk_id);
+            chunk_order_ids.push(order_id);
             let updated_file = File {
-                id: file.id,
-                url: (*file.url).to_string(),
-                number_of_chunks: file.number_of_chunks,
-                file_name: (*file.file_name).to_string(),
                 chunk_ids,
-                file_type: file.file_type,
-                owner: file.owner,
-                metadata: (*file.metadata).to_string(),
-                deleted_at: file.deleted_at,
-                created_at: file.created_at,
+                chunk_order_ids: Some(chunk_order_ids),
                 updated_at: time(),
-                accessors: file.accessors.clone(),
-                hash: file.hash,
+                ..file.clone()
             };
 
+            // The owner was charged for the chunk when the upload began, even if it is shared
             match insert_file(file.id, updated_file.clone()) {
-                Ok(_) => match update_user_info_chunk(file.owner, bytes_used) {
-                    Ok(_) => Ok(file),
-                    Err(e) => Err(e),
-                },
-                Err(e) => Err(String::from(e.to_string())),
+                Ok(_) => Ok(updated_file),
+                Err(e) => Err(AssetError::StorageError(e.to_string())),
             }
         }
         Err(e) => Err(e),
     }
 }
 
-// Inserts a chunk into the store
+// Inserts a chunk into the store and indexes it by its hash
 pub fn insert_chunk(
     file_id: FileID,
     chunk_data: ByteBuf,
     order_id: u64,
-) -> Result<ChunkID, String> {
-    CURRENT_CHUNK_ID.with(|current_chunk_id| {
-        let id = *current_chunk_id.borrow_mut();
-        *current_chunk_id.borrow_mut() = id + 1;
+    hash: Hash,
+) -> Result<ChunkID, AssetError> {
+    let id = next_chunk_id();
 
-        let created_at = time();
+    let created_at = time();
 
-        let hash = hash_bytes(&chunk_data);
+    let sha256 = sha256_bytes(&chunk_data);
 
-        let file_chunk = FileChunk {
-            file_id,
-            id,
-            chunk_data,
-            order_id,
-            metadata: String::from(""),
-            deleted_at: None,
-            created_at,
-            updated_at: created_at,
-            hash,
-        };
+    let file_chunk = FileChunk {
+        file_id,
+        id,
+        chunk_data,
+        order_id,
+        metadata: String::from(""),
+        deleted_at: None,
+        created_at,
+        updated_at: created_at,
+        hash,
+        sha256: Some(sha256),
+    };
+
+    store_chunk(file_chunk)
+}
 
-        match insert(file_chunk.id, file_chunk.clone()) {
-            Ok(None) => Ok(file_chunk.id),
-            Ok(Some(_)) => ic_cdk::trap("Attempting to overwrite chunk on insert"),
-            Err(e) => Err(String::from(e.to_string())),
+fn store_chunk(file_chunk: FileChunk) -> Result<ChunkID, AssetError> {
+    match insert(file_chunk.id, file_chunk.clone()) {
+        Ok(None) => {
+            let _ = CHUNK_HASH_INDEX.with(|p| {
+                p.borrow_mut()
+                    .insert(file_chunk.hash.to_vec(), file_chunk.id)
+            });
+            Ok(file_chunk.id)
         }
-    })
+        Ok(Some(_)) => ic_cdk::trap("Attempting to overwrite chunk on insert"),
+        Err(e) => Err(AssetError::StorageError(e.to_string())),
+    }
 }
 
-pub fn get_chunk_by_id(chunk_id: ChunkID) -> Result<FileChunk, String> {
+pub fn get_chunk_by_id(chunk_id: ChunkID) -> Result<FileChunk, AssetError> {
     match get(chunk_id) {
         Some(chunk) => Ok(chunk),
-        None => Err(String::from("file not found")),
+        None => Err(AssetError::ChunkNotFound),
     }
 }
 
-pub fn get_all_chunks_for_file(file: &File) -> Result<Vec<FileChunk>, String> {
-    let mut all_chunks: Vec<FileChunk> = vec![];
-
-    file.chunk_ids.iter().for_each(|chunk_id| {
-        CHUNK_MAP.with(|p| {
-            let found_chunk = p.borrow().get(&chunk_id);
-            if found_chunk.is_some() {
-                all_chunks.push(found_chunk.unwrap());
-            }
-        });
-    });
+#[allow(clippy::needless_return)]
+pub fn get_all_chunks_for_file(file: &File) -> Result<Vec<FileChunk>, AssetError> {
+    let all_chunks = file
+        .chunk_ids
+        .iter()
+        .zip(get_chunk_order_ids(file))
+        .filter_map(|(chunk_id, order_id)| get_chunk_for_file(file, *chunk_id, order_id))
+        .collect();
 
     return Ok(all_chunks);
 }
 
 pub fn get_chunk_by_order_id_for_file(file: &File, order_id: u64) -> Option<FileChunk> {
-    let mut found_chunk: Option<FileChunk> = None;
-
-    let mut found_chunks: Vec<FileChunk> = vec![];
-    file.chunk_ids.iter().for_each(|chunk_id| {
-        CHUNK_MAP.with(|p| {
-            let found_chunk = p.borrow().get(&chunk_id);
-            if found_chunk.is_some() {
-                found_chunks.push(found_chunk.unwrap());
-            }
-        });
-    });
+    match &file.chunk_order_ids {
+        Some(chunk_order_ids) => chunk_order_ids
+            .iter()
+            .position(|chunk_order_id| *chunk_order_id == order_id)
+            .and_then(|index| get_chunk_for_file(file, file.chunk_ids[index], order_id)),
+        None => file
+            .chunk_ids
+            .iter()
+            .filter_map(|chunk_id| get(*chunk_id))
+            .find(|chunk| chunk.order_id == order_id),
+    }
+}
 
-    found_chunks.iter().for_each(|chunk| {
-        if chunk.order_id == order_id {
-            found_chunk = Some(chunk.clone())
-        }
-    });
+/// Total size in bytes of the chunks the file has
+pub fn get_content_size(file: &File) -> u64 {
+    get_all_chunks_for_file(file)
+        .unwrap_or_default()
+        .iter()
+        .map(|chunk| chunk.chunk_data.len() as u64)
+        .sum()
+}
+
+/// SHA-256 of the full content of a file, taken over its chunks in order
+pub fn hash_file_content(file: &File) -> Hash {
+    let mut chunks = get_all_chunks_for_file(file).unwrap_or_default();
+    chunks.sort_by_key(|chunk| chunk.order_id);
 
-    return found_chunk;
+    let mut hasher = Sha256::new();
+    chunks
+        .iter()
+        .for_each(|chunk| hasher.update(&chunk.chunk_data));
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use candid::Principal;
+
+    use crate::models::file::tests::test_file;
+
+    fn store_test_chunk(id: ChunkID, data: &[u8]) -> Hash {
+        let hash = hash_bytes(data);
+        store_chunk(FileChunk {
+            id,
+            file_id: 0,
+            order_id: 0,
+            chunk_data: ByteBuf::from(data.to_vec()),
+            metadata: String::new(),
+            deleted_at: None,
+            created_at: 0,
+            updated_at: 0,
+            hash,
+            sha256: None,
+        })
+        .unwrap();
+        hash
+    }
+
+    #[test]
+    fn reference_keys_group_a_chunks_files() {
+        let key = reference_key(1, 300);
+        assert_eq!(file_id_from_reference_key(&key), 300);
+        assert!(reference_key(1, 300) < reference_key(2, 0));
+        assert!(reference_key(1, 2) < reference_key(1, 256));
+    }
+
+    #[test]
+    fn shared_chunk_is_kept_until_its_last_file_releases_it() {
+        let hash = store_test_chunk(1, b"shared");
+        // A file in the trash and an earlier version both still hold references
+        insert_chunk_reference(1, 10);
+        insert_chunk_reference(1, 11);
+        assert_eq!(get_chunk_file_ids(1), vec![10, 11]);
+
+        assert_eq!(release_chunk(1, 10), 0);
+        assert!(get(1).is_some());
+        assert_eq!(find_chunk_by_hash(&hash), Some(1));
+
+        assert_eq!(release_chunk(1, 11), 6);
+        assert!(get(1).is_none());
+        assert_eq!(find_chunk_by_hash(&hash), None);
+    }
+
+    #[test]
+    fn moved_references_keep_the_chunk() {
+        store_test_chunk(2, b"replaced");
+        insert_chunk_reference(2, 20);
+        // Committing new content moves the upload's chunks onto the file it replaces
+        move_chunk_references(&[2], 20, 21);
+        assert_eq!(get_chunk_file_ids(2), vec![21]);
+
+        assert_eq!(release_chunk(2, 20), 0);
+        assert!(get(2).is_some());
+        assert_eq!(release_chunk(2, 21), 8);
+        assert!(get(2).is_none());
+    }
+
+    #[test]
+    fn released_duplicate_leaves_the_hash_index_alone() {
+        let hash = store_test_chunk(3, b"duplicate");
+        // A chunk stored before chunks were shared, with the same content
+        store_test_chunk(4, b"duplicate");
+        CHUNK_HASH_INDEX.with(|p| p.borrow_mut().insert(hash.to_vec(), 3).unwrap());
+
+        assert_eq!(release_chunk(4, 30), 9);
+        assert_eq!(find_chunk_by_hash(&hash), Some(3));
+    }
+
+    #[test]
+    fn chunk_is_returned_as_part_of_the_readers_file() {
+        // Stored with the file ID and order ID of whichever file uploaded it first
+        store_test_chunk(5, b"content");
+        let file = File {
+            chunk_ids: vec![9, 5],
+            chunk_order_ids: Some(vec![0, 1]),
+            ..test_file(50, Principal::anonymous())
+        };
+
+        let chunk = get_chunk_in_file(&file, 5).unwrap();
+        assert_eq!((chunk.file_id, chunk.order_id), (50, 1));
+        assert!(get_chunk_in_file(&test_file(51, Principal::anonymous()), 5).is_none());
+    }
 }
@@ -0,0 +1,94 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::{cell::Cell as StableCell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{api::error::AssetError, auth::file::CHUNK_SIZE, models::config::Config};
+
+use super::memory::{get_memory, Memory, CONFIG_MEMORY_ID};
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+/// Uploads get at least an hour (in nano seconds) between chunks before they can be swept
+const MIN_UPLOAD_TTL: u64 = 3600000000000;
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CONFIG_MEMORY_ID), Config::default())
+            .expect("Failed to initialise the config")
+    );
+}
+
+pub fn get_config() -> Config {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn set_config(config: Config) -> Result<Config, AssetError> {
+    match validate_config(&config) {
+        Ok(_) => {
+            match CONFIG.with(|current_config| current_config.borrow_mut().set(config.clone())) {
+                Ok(_) => Ok(config),
+                Err(_) => Err(AssetError::StorageError(String::from(
+                    "Failed to save the config",
+                ))),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn validate_config(config: &Config) -> Result<(), AssetError> {
+    let invalid = |message: &str| Err(AssetError::InvalidConfig(String::from(message)));
+
+    if config.chunk_size == 0 || config.chunk_size > CHUNK_SIZE {
+        return invalid("chunk_size must be between 1 byte and 1.9MB");
+    }
+    if config.max_chunks == 0 {
+        return invalid("max_chunks must be at least 1");
+    }
+    if config.max_file_size < config.chunk_size {
+        return invalid("max_file_size must fit at least one chunk");
+    }
+    if config.upload_ttl < MIN_UPLOAD_TTL {
+        return invalid("upload_ttl must be at least one hour");
+    }
+    if config.calls_per_window == 0 || config.call_window < config.calls_per_window {
+        return invalid("calls_per_window must be at least 1 and at most call_window");
+    }
+    if config.files_per_day == 0 {
+        return invalid("files_per_day must be at least 1");
+    }
+    if config.warning_decay == 0 {
+        return invalid("warning_decay must be at least 1 nano second");
+    }
+    if config.trash_retention == 0 {
+        return invalid("trash_retention must be at least 1 nano second");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_ttl_has_a_lower_bound() {
+        let config = |upload_ttl| Config {
+            upload_ttl,
+            ..Config::default()
+        };
+
+        assert!(validate_config(&Config::default()).is_ok());
+        assert!(validate_config(&config(MIN_UPLOAD_TTL)).is_ok());
+        assert!(validate_config(&config(0)).is_err());
+        assert!(validate_config(&config(MIN_UPLOAD_TTL - 1)).is_err());
+    }
+}
@@ -3,21 +3,47 @@ use candid::Principal;
 use candid::{Decode, Encode};
 use ic_cdk::api::time;
 use ic_stable_structures::btreemap::InsertError;
-use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::cell::Cell as StableCell;
+use ic_stable_structures::{StableBTreeMap, Storable};
 use serde_bytes::ByteBuf;
+use sha2::Sha256;
+use sha3::{Digest, Sha3_256};
 use std::collections::HashSet;
 use std::{borrow::Cow, cell::RefCell};
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
-
-use crate::models::file::{hash_bytes, File, FileType};
+use crate::api::error::AssetError;
+use crate::api::file::FileFilter;
+use crate::models::file::{File, FileChunk, FileType, Hash, UploadStatus, Visibility};
+use crate::models::metadata::FileMetadata;
 
 const MAX_KEY_SIZE: u32 = 8;
+// Owner index keys are the principal length, the principal (at most 29 bytes) and the file ID
+const MAX_OWNER_KEY_SIZE: u32 = 38;
+// Created at index keys are the creation time and the file ID
+const MAX_CREATED_AT_KEY_SIZE: u32 = 16;
+const MAX_PAGE_SIZE: usize = 100;
+// The most index entries a listing reads, so filters that match few files can't exhaust the instruction limit
+const MAX_SCANNED_PER_PAGE: usize = 1000;
 const MAX_VALUE_SIZE: u32 = 20000000;
+// Trash index keys are the time the file was deleted and the file ID
+const MAX_TRASH_KEY_SIZE: u32 = 16;
+
+/// Deleted files can be restored for 30 days by default (in nano seconds)
+pub const TRASH_RETENTION: u64 = 30 * 86400000000000;
 
-use super::chunks::{insert_chunk, remove_chunk};
-use super::users::update_user_info_file;
+use super::certification::{certify_file, uncertify_file};
+use super::chunks::{
+    get_all_chunks_for_file, get_content_size, move_chunk_references, put_chunk, release_chunk,
+    ChunkID,
+};
+use super::config::get_config;
+use super::memory::{
+    get_memory, Memory, CREATED_AT_INDEX_MEMORY_ID, CURRENT_FILE_ID_MEMORY_ID, FILES_MEMORY_ID,
+    OWNER_INDEX_MEMORY_ID, PENDING_UPLOADS_MEMORY_ID, TRASH_INDEX_MEMORY_ID,
+};
+use super::search::{index_file, unindex_file};
+use super::users::{refund_user_bytes, update_user_info_file};
+use super::versions::{insert_version, prune_versions, remove_versions};
 
 pub type FileID = u64;
 
@@ -32,7 +58,7 @@ pub type FileID = u64;
 // The `Storable` trait is already implemented for many common types (e.g. u64, String),
 // so you can use those directly without implementing the `Storable` trait for them.
 impl Storable for File {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -42,145 +68,981 @@ impl Storable for File {
 }
 
 thread_local! {
-    pub static CURRENT_FILE_ID: RefCell<FileID> = RefCell::default();
-    // The memory manager is used for simulating multiple memories. Given a `MemoryId` it can
-    // return a memory that can be used by stable structures.
-    static FILE_MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static CURRENT_FILE_ID: RefCell<StableCell<FileID, Memory>> = RefCell::new(
+        StableCell::init(get_memory(CURRENT_FILE_ID_MEMORY_ID), 0)
+            .expect("Failed to initialise the file ID counter")
+    );
 
     static FILE_MAP: RefCell<StableBTreeMap<Memory, FileID, File>> = RefCell::new(
         StableBTreeMap::init(
-            FILE_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+            get_memory(FILES_MEMORY_ID),
             MAX_KEY_SIZE,
             MAX_VALUE_SIZE
         )
     );
+
+    // IDs of files whose upload hasn't been committed, so they can be found without iterating FILE_MAP
+    static PENDING_UPLOADS: RefCell<StableBTreeMap<Memory, FileID, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(PENDING_UPLOADS_MEMORY_ID),
+            MAX_KEY_SIZE,
+            0
+        )
+    );
+
+    // Maps each owner to the IDs of their files, so they can be found without iterating FILE_MAP
+    static OWNER_INDEX: RefCell<StableBTreeMap<Memory, Vec<u8>, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(OWNER_INDEX_MEMORY_ID),
+            MAX_OWNER_KEY_SIZE,
+            0
+        )
+    );
+
+    // Every file in order of creation, so files can be listed by creation time without iterating FILE_MAP
+    static CREATED_AT_INDEX: RefCell<StableBTreeMap<Memory, Vec<u8>, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(CREATED_AT_INDEX_MEMORY_ID),
+            MAX_CREATED_AT_KEY_SIZE,
+            0
+        )
+    );
+
+    // Deleted files in the order they were deleted, so expired ones can be purged without iterating FILE_MAP
+    static TRASH_INDEX: RefCell<StableBTreeMap<Memory, Vec<u8>, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(TRASH_INDEX_MEMORY_ID),
+            MAX_TRASH_KEY_SIZE,
+            0
+        )
+    );
 }
 
+/// Creates a file with its first chunk
+/// The file stays pending, and invisible, until the upload is committed
 pub fn create_file(
     first_chunk: ByteBuf,
     file_name: String,
     number_of_chunks: u64,
     file_type: String,
     owner: Principal,
-) -> Result<File, String> {
-    let bytes_used = first_chunk.len() as u64;
+) -> Result<File, AssetError> {
+    match begin_upload(file_name, number_of_chunks, file_type, owner) {
+        Ok(file) => put_chunk(&file, first_chunk, 0),
+        Err(e) => Err(e),
+    }
+}
+
+/// The bytes charged to the owner while a file is uploading, the most it could grow to
+pub fn reserved_bytes(number_of_chunks: u64, chunk_size: u64) -> u64 {
+    number_of_chunks.saturating_mul(chunk_size)
+}
+
+/// Creates a pending file with no chunks, which are added with `put_chunk`
+/// The owner is charged for the largest the file could be until the upload is committed
+pub fn begin_upload(
+    file_name: String,
+    number_of_chunks: u64,
+    file_type: String,
+    owner: Principal,
+) -> Result<File, AssetError> {
     match FileType::convert_to_file_type(file_type.as_str()) {
-        Ok(file_type) => CURRENT_FILE_ID.with(|current_id| {
-            let id = *current_id.borrow_mut();
-            *current_id.borrow_mut() = id + 1;
-            let hash = hash_bytes(&first_chunk);
-
-            match insert_chunk(id, first_chunk, 0) {
-                Ok(chunk_id) => {
-                    let url;
-                    if option_env!("DFX_NETWORK") == Some("local") {
-                        url = format!(
-                            "http://{}.localhost:4943/{}/{}",
-                            ic_cdk::api::id(),
-                            file_type.url_slug(),
-                            id
-                        );
-                    } else {
-                        url = format!(
-                            "https://{}.raw.ic0.app/{}/{}",
-                            ic_cdk::api::id(),
-                            file_type.url_slug(),
-                            id
-                        );
-                    }
+        Ok(file_type) => {
+            let id = next_file_id();
 
-                    let created_at = time();
-
-                    let mut accessors = HashSet::new();
-                    accessors.insert(owner);
-
-                    let file = File {
-                        id,
-                        url,
-                        chunk_ids: vec![chunk_id],
-                        number_of_chunks,
-                        file_name,
-                        file_type,
-                        owner,
-                        metadata: String::from(""),
-                        deleted_at: None,
-                        created_at,
-                        updated_at: created_at,
-                        accessors,
-                        hash,
-                    };
-
-                    match insert_file(file.id, file.clone()) {
-                        Ok(_) => match update_user_info_file(owner, &file, bytes_used) {
-                            Ok(_) => Ok(file),
-                            Err(e) => Err(e),
-                        },
-                        Err(e) => Err(String::from(e.to_string())),
+            let url = if option_env!("DFX_NETWORK") == Some("local") {
+                format!(
+                    "http://{}.localhost:4943/{}/{}",
+                    ic_cdk::api::id(),
+                    file_type.url_slug(),
+                    id
+                )
+            } else {
+                format!(
+                    "https://{}.ic0.app/{}/{}",
+                    ic_cdk::api::id(),
+                    file_type.url_slug(),
+                    id
+                )
+            };
+
+            let created_at = time();
+
+            let mut accessors = HashSet::new();
+            accessors.insert(owner);
+
+            let file = File {
+                id,
+                url,
+                chunk_ids: vec![],
+                chunk_order_ids: Some(vec![]),
+                number_of_chunks,
+                file_name,
+                file_type,
+                owner,
+                metadata: String::from(""),
+                deleted_at: None,
+                created_at,
+                updated_at: created_at,
+                accessors,
+                // Set once the upload is committed
+                hash: [0; 32],
+                sha256: None,
+                upload_status: Some(UploadStatus::Pending),
+                visibility: Some(Visibility::Public),
+                share_salt: Some(0),
+                chunk_size: Some(get_config().chunk_size),
+                version: Some(1),
+                replaces: None,
+                file_metadata: None,
+            };
+
+            match insert_file(file.id, file.clone()) {
+                Ok(_) => match update_user_info_file(owner, file.reserved_bytes()) {
+                    Ok(_) => {
+                        insert_pending_upload(file.id);
+                        insert_owner_index(file.owner, file.id);
+                        insert_created_at_index(&file);
+                        Ok(file)
                     }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(AssetError::StorageError(e.to_string())),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Begins the upload of new content for a committed file, which is uploaded like any other file
+/// Committing the upload makes it the file's next version, keeping the file's ID and URL
+/// The new content must be served from the same URL, so it can't change between images and videos
+pub fn begin_replacement(
+    file: &File,
+    number_of_chunks: u64,
+    file_type: String,
+) -> Result<File, AssetError> {
+    match FileType::convert_to_file_type(file_type.as_str()) {
+        Ok(new_file_type) if new_file_type.url_slug() != file.file_type.url_slug() => {
+            Err(AssetError::InvalidFileType)
+        }
+        Ok(_) => match begin_upload(
+            file.file_name.clone(),
+            number_of_chunks,
+            file_type,
+            file.owner,
+        ) {
+            Ok(upload) => {
+                let upload = File {
+                    url: file.url.clone(),
+                    replaces: Some(file.id),
+                    ..upload
+                };
+                match insert_file(upload.id, upload.clone()) {
+                    Ok(_) => Ok(upload),
+                    Err(e) => Err(AssetError::StorageError(e.to_string())),
                 }
-                Err(e) => Err(e),
             }
-        }),
+            Err(e) => Err(e),
+        },
         Err(e) => Err(e),
     }
 }
 
-pub fn delete_file(file_id: FileID) -> Result<String, String> {
+/// Completes an upload, making the file visible
+/// Uploads from `begin_replacement` become the next version of the file they replace instead
+///
+/// Checks that every chunk from 0 to `number_of_chunks - 1` was uploaded exactly once
+/// and that the SHA-256 of the full content matches what the client expects
+pub fn commit_upload(file: &File, expected_sha256: Hash) -> Result<File, AssetError> {
+    if file.is_committed() {
+        return Err(AssetError::UploadComplete);
+    }
+
+    let chunks = get_all_chunks_for_file(file)?;
+    let (hash, sha256, file_size) = hash_upload(
+        file.number_of_chunks,
+        file.get_chunk_size(),
+        chunks,
+        expected_sha256,
+    )?;
+
+    let committed_file = File {
+        hash,
+        sha256: Some(sha256),
+        upload_status: Some(UploadStatus::Committed),
+        updated_at: time(),
+        ..file.clone()
+    };
+
+    let result = match committed_file.replaces {
+        Some(file_id) => replace_content(file_id, &committed_file),
+        None => match insert_file(committed_file.id, committed_file.clone()) {
+            Ok(_) => {
+                remove_pending_upload(&committed_file.id);
+                certify_file(&committed_file);
+                index_file(&committed_file);
+                Ok(committed_file.clone())
+            }
+            Err(e) => Err(AssetError::StorageError(e.to_string())),
+        },
+    };
+
+    if result.is_ok() {
+        // Swap the reservation for the real size
+        let _ = refund_user_bytes(
+            committed_file.owner,
+            committed_file.reserved_bytes().saturating_sub(file_size),
+        );
+    }
+    result
+}
+
+/// Checks every chunk of the upload is there and that the content hashes to what the uploader expected
+/// Every chunk but the last has to be exactly `chunk_size` bytes, so byte ranges can be mapped onto chunks
+/// Returns the SHA3-256 and SHA-256 hashes of the content and its size in bytes
+fn hash_upload(
+    number_of_chunks: u64,
+    chunk_size: u64,
+    mut chunks: Vec<FileChunk>,
+    expected_sha256: Hash,
+) -> Result<(Hash, Hash, u64), AssetError> {
+    chunks.sort_by_key(|chunk| chunk.order_id);
+
+    if chunks.len() as u64 != number_of_chunks
+        || chunks
+            .iter()
+            .enumerate()
+            .any(|(index, chunk)| chunk.order_id != index as u64)
+    {
+        return Err(AssetError::MissingChunks);
+    }
+    if chunks[..chunks.len().saturating_sub(1)]
+        .iter()
+        .any(|chunk| chunk.chunk_data.len() as u64 != chunk_size)
+    {
+        return Err(AssetError::ChunkTooSmall { chunk_size });
+    }
+
+    let mut sha3_hasher = Sha3_256::new();
+    let mut sha256_hasher = Sha256::new();
+    let mut file_size = 0;
+    chunks.iter().for_each(|chunk| {
+        sha3_hasher.update(&chunk.chunk_data);
+        sha256_hasher.update(&chunk.chunk_data);
+        file_size += chunk.chunk_data.len() as u64;
+    });
+    let sha256: Hash = sha256_hasher.finalize().into();
+
+    match sha256 == expected_sha256 {
+        true => Ok((sha3_hasher.finalize().into(), sha256, file_size)),
+        false => Err(AssetError::HashMismatch),
+    }
+}
+
+/// Keeps the file's content as an earlier version and moves the committed upload's content into it
+/// The upload itself is removed, its chunks now belong to the file
+fn replace_content(file_id: FileID, upload: &File) -> Result<File, AssetError> {
+    let file = get_file_by_id(&file_id)?;
+    if !file.is_committed() {
+        return Err(AssetError::FileNotFound);
+    }
+
+    insert_version(&file)?;
+    move_chunk_references(&upload.chunk_ids, upload.id, file.id);
+
+    let replaced_file = File {
+        file_type: upload.file_type,
+        chunk_ids: upload.chunk_ids.clone(),
+        chunk_order_ids: upload.chunk_order_ids.clone(),
+        number_of_chunks: upload.number_of_chunks,
+        chunk_size: upload.chunk_size,
+        hash: upload.hash,
+        sha256: upload.sha256,
+        version: Some(file.get_version() + 1),
+        updated_at: time(),
+        ..file
+    };
+
+    match insert_file(replaced_file.id, replaced_file.clone()) {
+        Ok(_) => {
+            remove_file(&upload.id);
+            remove_pending_upload(&upload.id);
+            remove_owner_index(upload);
+            remove_created_at_index(upload);
+            certify_file(&replaced_file);
+            prune_versions(&replaced_file);
+            Ok(replaced_file)
+        }
+        Err(e) => Err(AssetError::StorageError(e.to_string())),
+    }
+}
+
+/// Renames the file if a name is given, and replaces all of its metadata
+pub fn update_file_metadata(
+    file: &File,
+    file_name: Option<String>,
+    metadata: FileMetadata,
+) -> Result<File, AssetError> {
+    match update_file(File {
+        file_name: file_name.map_or_else(
+            || file.file_name.clone(),
+            |file_name| file_name.trim().to_string(),
+        ),
+        file_metadata: Some(metadata.normalise()),
+        ..file.clone()
+    }) {
+        Ok(updated_file) => {
+            unindex_file(file);
+            index_file(&updated_file);
+            Ok(updated_file)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn set_visibility(file: &File, visibility: Visibility) -> Result<File, AssetError> {
+    update_file(File {
+        visibility: Some(visibility),
+        ..file.clone()
+    })
+}
+
+pub fn grant_access(file: &File, principal: Principal) -> Result<File, AssetError> {
+    let mut accessors = file.accessors.clone();
+    accessors.insert(principal);
+
+    update_file(File {
+        accessors,
+        ..file.clone()
+    })
+}
+
+pub fn revoke_access(file: &File, principal: Principal) -> Result<File, AssetError> {
+    if principal == file.owner {
+        return Err(AssetError::CannotRevokeOwner);
+    }
+
+    let mut accessors = file.accessors.clone();
+    accessors.remove(&principal);
+
+    update_file(File {
+        accessors,
+        ..file.clone()
+    })
+}
+
+/// Changes the salt share links are signed with, so every link to the file stops working
+pub fn rotate_share_salt(file: &File) -> Result<File, AssetError> {
+    update_file(File {
+        share_salt: Some(file.get_share_salt().wrapping_add(1)),
+        ..file.clone()
+    })
+}
+
+/// Saves changes to a file's settings, which leave its content and certification alone
+fn update_file(file: File) -> Result<File, AssetError> {
+    let updated_file = File {
+        updated_at: time(),
+        ..file
+    };
+
+    match insert_file(updated_file.id, updated_file.clone()) {
+        Ok(_) => Ok(updated_file),
+        Err(e) => Err(AssetError::StorageError(e.to_string())),
+    }
+}
+
+/// Moves a file to the trash, where it can be restored until the trash retention has passed
+/// Uploads which never committed have nothing worth restoring, so they are removed straight away
+/// The owner is still charged for the file until it is purged
+pub fn delete_file(file_id: FileID) -> Result<String, AssetError> {
     match get_file_by_id(&file_id) {
+        Ok(file) if !file.is_committed() => {
+            remove_file_and_chunks(&file);
+            Ok(String::from("File deleted"))
+        }
         Ok(file) => {
-            let chunks_to_delete = file.chunk_ids.clone();
+            let deleted_file = File {
+                deleted_at: Some(time()),
+                ..file
+            };
 
-            remove_file(&file_id);
+            match insert_file(deleted_file.id, deleted_file.clone()) {
+                Ok(_) => {
+                    insert_trash_index(&deleted_file);
+                    uncertify_file(&deleted_file);
+                    unindex_file(&deleted_file);
+                    Ok(String::from("File moved to trash"))
+                }
+                Err(e) => Err(AssetError::StorageError(e.to_string())),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
 
-            chunks_to_delete.into_iter().for_each(|chunk| {
-                remove_chunk(chunk);
-            });
+/// Takes a file back out of the trash, as long as it hasn't expired
+pub fn restore_file(file: &File) -> Result<File, AssetError> {
+    check_restorable(file, time())?;
 
-            Ok(String::from("File deleted"))
+    match update_file(File {
+        deleted_at: None,
+        ..file.clone()
+    }) {
+        Ok(restored_file) => {
+            remove_trash_index(file);
+            certify_file(&restored_file);
+            index_file(&restored_file);
+            Ok(restored_file)
         }
         Err(e) => Err(e),
     }
 }
 
-pub fn delete_all_files_by_prinicipal(principal: Principal) -> Vec<File> {
-    let mut all_files: Vec<File> = vec![];
+/// A file can be restored until it expires, even if it hasn't been purged yet
+fn check_restorable(file: &File, now: u64) -> Result<(), AssetError> {
+    if !file.is_deleted() {
+        return Err(AssetError::NotInTrash);
+    }
+    match trash_expires_at(file) <= now {
+        true => Err(AssetError::FileNotFound),
+        false => Ok(()),
+    }
+}
+
+/// Gets the files in the owner's trash, in order of creation
+pub fn get_trash_by_owner(owner: Principal) -> Vec<File> {
+    get_file_ids_by_owner(owner, None)
+        .iter()
+        .filter_map(get_file)
+        .filter(|file| file.is_deleted())
+        .collect()
+}
+
+/// Permanently removes up to `limit` files whose time in the trash has run out
+/// Returns the ID of each purged file with the number of bytes it freed
+pub fn purge_expired_trash(limit: usize) -> Vec<(FileID, u64)> {
+    let deleted_before = time().saturating_sub(get_config().trash_retention);
 
-    // TODO: this needs fixing since it will not work
-    // iterating over the file map exceeds the execution limit
-    // So we need to store a way of mapping principals to files, so that we can retrieve the files by ID one by one
-    // Rather than looping over the whole array
-    FILE_MAP.with(|p| {
-        p.borrow().iter().for_each(|(_, file)| {
-            if file.owner == principal {
-                all_files.push(file.clone())
+    get_expired_trash_keys(deleted_before, limit)
+        .iter()
+        .filter_map(|key| {
+            let file_id = file_id_from_index_key(key);
+            match get_file(&file_id) {
+                Some(file) => Some((file_id, remove_file_and_chunks(&file))),
+                // The file has already gone, so just clean up the index
+                None => {
+                    TRASH_INDEX.with(|p| p.borrow_mut().remove(key));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The time a deleted file will be purged
+pub fn trash_expires_at(file: &File) -> u64 {
+    file.deleted_at
+        .unwrap_or_default()
+        .saturating_add(get_config().trash_retention)
+}
+
+/// Removes a file and the chunks no other file uses, refunding its owner
+/// Returns the number of bytes freed
+pub fn remove_file_and_chunks(file: &File) -> u64 {
+    remove_file(&file.id);
+    remove_pending_upload(&file.id);
+    remove_owner_index(file);
+    remove_created_at_index(file);
+    remove_trash_index(file);
+    uncertify_file(file);
+    unindex_file(file);
+
+    // Owners are charged for the whole file, even the chunks shared with other files
+    // Uploads which never committed are still charged their reservation
+    let mut bytes_charged = match file.upload_status {
+        Some(UploadStatus::Pending) => file.reserved_bytes(),
+        _ => get_content_size(file),
+    };
+
+    // Versions of the file can share chunks, each is released once
+    let mut chunk_ids: HashSet<ChunkID> = file.chunk_ids.iter().copied().collect();
+    remove_versions(file.id).iter().for_each(|version| {
+        bytes_charged += version.size;
+        chunk_ids.extend(&version.chunk_ids);
+    });
+
+    let bytes_freed = chunk_ids
+        .iter()
+        .map(|chunk_id| release_chunk(*chunk_id, file.id))
+        .sum();
+    let _ = refund_user_bytes(file.owner, bytes_charged);
+
+    bytes_freed
+}
+
+/// Deletes up to `limit` files owned by the principal, along with their chunks
+/// Returns the ID of each deleted file with the number of bytes it freed
+pub fn delete_files_by_owner(owner: Principal, limit: usize) -> Vec<(FileID, u64)> {
+    let mut deleted_files: Vec<(FileID, u64)> = vec![];
+
+    get_file_ids_by_owner(owner, Some(limit))
+        .into_iter()
+        .for_each(|file_id| match get_file(&file_id) {
+            Some(file) => deleted_files.push((file_id, remove_file_and_chunks(&file))),
+            // The file has already gone, so just clean up the index
+            None => {
+                OWNER_INDEX.with(|p| p.borrow_mut().remove(&owner_index_key(owner, file_id)));
             }
         });
+
+    deleted_files
+}
+
+/// Gets the IDs of files owned by the principal, in order of creation
+pub fn get_file_ids_by_owner(owner: Principal, limit: Option<usize>) -> Vec<FileID> {
+    OWNER_INDEX.with(|p| {
+        p.borrow()
+            .range(owner_index_prefix(owner), None)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, _)| file_id_from_index_key(&key))
+            .collect()
+    })
+}
+
+pub fn count_files_by_owner(owner: Principal) -> u64 {
+    OWNER_INDEX.with(|p| p.borrow().range(owner_index_prefix(owner), None).count() as u64)
+}
+
+fn owner_index_prefix(owner: Principal) -> Vec<u8> {
+    let principal = owner.as_slice();
+    let mut prefix = vec![principal.len() as u8];
+    prefix.extend_from_slice(principal);
+    prefix
+}
+
+// File IDs are big-endian so that keys sort in order of creation
+fn owner_index_key(owner: Principal, file_id: FileID) -> Vec<u8> {
+    let mut key = owner_index_prefix(owner);
+    key.extend_from_slice(&file_id.to_be_bytes());
+    key
+}
+
+// Every index ends its keys with the file ID
+fn file_id_from_index_key(key: &[u8]) -> FileID {
+    let mut file_id = [0; 8];
+    file_id.copy_from_slice(&key[key.len() - 8..]);
+    FileID::from_be_bytes(file_id)
+}
+
+fn insert_owner_index(owner: Principal, file_id: FileID) {
+    let _ = OWNER_INDEX.with(|p| p.borrow_mut().insert(owner_index_key(owner, file_id), ()));
+}
+
+fn remove_owner_index(file: &File) {
+    OWNER_INDEX.with(|p| p.borrow_mut().remove(&owner_index_key(file.owner, file.id)));
+}
+
+fn created_at_index_key(file: &File) -> Vec<u8> {
+    let mut key = file.created_at.to_be_bytes().to_vec();
+    key.extend_from_slice(&file.id.to_be_bytes());
+    key
+}
+
+fn trash_index_key(file: &File) -> Option<Vec<u8>> {
+    let mut key = file.deleted_at?.to_be_bytes().to_vec();
+    key.extend_from_slice(&file.id.to_be_bytes());
+    Some(key)
+}
+
+fn deleted_at_from_trash_key(key: &[u8]) -> u64 {
+    let mut deleted_at = [0; 8];
+    deleted_at.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(deleted_at)
+}
+
+// Keys sort by deletion time, so the expired files come first
+fn get_expired_trash_keys(deleted_before: u64, limit: usize) -> Vec<Vec<u8>> {
+    TRASH_INDEX.with(|p| {
+        p.borrow()
+            .iter()
+            .take_while(|(key, _)| deleted_at_from_trash_key(key) < deleted_before)
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect()
+    })
+}
+
+fn insert_trash_index(file: &File) {
+    if let Some(key) = trash_index_key(file) {
+        let _ = TRASH_INDEX.with(|p| p.borrow_mut().insert(key, ()));
+    }
+}
+
+fn remove_trash_index(file: &File) {
+    if let Some(key) = trash_index_key(file) {
+        TRASH_INDEX.with(|p| p.borrow_mut().remove(&key));
+    }
+}
+
+fn insert_created_at_index(file: &File) {
+    let _ = CREATED_AT_INDEX.with(|p| p.borrow_mut().insert(created_at_index_key(file), ()));
+}
+
+fn remove_created_at_index(file: &File) {
+    CREATED_AT_INDEX.with(|p| p.borrow_mut().remove(&created_at_index_key(file)));
+}
+
+/// Lists committed files matching the filter, in order of creation, leaving out the trash
+///
+/// The owner index is used when filtering by owner, otherwise the created at index.
+/// At most `MAX_SCANNED_PER_PAGE` index entries are read, so a page can hold fewer than
+/// `limit` files while more remain. The cursor is the index key to carry on from
+pub fn list_files(
+    filter: &FileFilter,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+) -> Result<(Vec<File>, Option<Vec<u8>>), AssetError> {
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    match filter.owner {
+        Some(owner) => {
+            let prefix = owner_index_prefix(owner);
+            let offset = match cursor {
+                Some(cursor) if cursor.starts_with(&prefix) => {
+                    Some(cursor[prefix.len()..].to_vec())
+                }
+                Some(_) => return Err(AssetError::InvalidCursor),
+                None => None,
+            };
+
+            Ok(OWNER_INDEX.with(|p| {
+                collect_page(
+                    p.borrow().range(prefix, offset).map(|(key, _)| key),
+                    filter,
+                    limit,
+                )
+            }))
+        }
+        None => {
+            let offset = match cursor {
+                Some(cursor) if cursor.len() == MAX_CREATED_AT_KEY_SIZE as usize => cursor,
+                Some(_) => return Err(AssetError::InvalidCursor),
+                None => filter.created_after.unwrap_or(0).to_be_bytes().to_vec(),
+            };
+
+            Ok(CREATED_AT_INDEX.with(|p| {
+                collect_page(
+                    p.borrow().range(vec![], Some(offset)).map(|(key, _)| key),
+                    filter,
+                    limit,
+                )
+            }))
+        }
+    }
+}
+
+fn collect_page(
+    keys: impl Iterator<Item = Vec<u8>>,
+    filter: &FileFilter,
+    limit: usize,
+) -> (Vec<File>, Option<Vec<u8>>) {
+    let mut files: Vec<File> = vec![];
+
+    for (scanned, key) in keys.enumerate() {
+        if files.len() >= limit || scanned >= MAX_SCANNED_PER_PAGE {
+            return (files, Some(key));
+        }
+
+        match get_file(&file_id_from_index_key(&key)) {
+            // Both indexes are in order of creation, so no later file can match
+            Some(file)
+                if filter
+                    .created_before
+                    .is_some_and(|before| file.created_at > before) =>
+            {
+                return (files, None)
+            }
+            Some(file)
+                if file.is_committed()
+                    && !file.is_deleted()
+                    && file_matches_filter(&file, filter) =>
+            {
+                files.push(file)
+            }
+            _ => (),
+        }
+    }
+
+    (files, None)
+}
+
+fn file_matches_filter(file: &File, filter: &FileFilter) -> bool {
+    filter
+        .file_type
+        .is_none_or(|file_type| file.file_type == file_type)
+        && filter.owner.is_none_or(|owner| file.owner == owner)
+        && filter
+            .created_after
+            .is_none_or(|after| file.created_at >= after)
+        && filter
+            .created_before
+            .is_none_or(|before| file.created_at <= before)
+}
+
+/// Calls `f` with every stored file
+/// This iterates the whole file map so should only be used where the instruction limit is high,
+/// such as in `init` and `post_upgrade`
+pub fn for_each_file(mut f: impl FnMut(&File)) {
+    FILE_MAP.with(|p| p.borrow().iter().for_each(|(_, file)| f(&file)));
+}
+
+/// Calls `f` with the stored files from `start` on, in the map's key order, until it returns false
+/// Returns the ID of the file to carry on from, None once every file has been seen
+pub fn for_each_file_from(
+    start: Option<FileID>,
+    mut f: impl FnMut(&File) -> bool,
+) -> Option<FileID> {
+    FILE_MAP.with(|p| {
+        p.borrow()
+            .range(vec![], start.map(|file_id| file_id.to_bytes().to_vec()))
+            .find(|(_, file)| !f(file))
+            .map(|(file_id, _)| file_id)
+    })
+}
+
+/// Adds files saved before the indexes existed to them
+/// Files saved before uploads had to be committed, which are still missing chunks,
+/// are added to the pending uploads so they can be cleaned up
+pub fn index_files() {
+    let mut incomplete_files: Vec<FileID> = vec![];
+    let mut indexed_files: Vec<File> = vec![];
+
+    for_each_file(|file| {
+        if file.upload_status.is_none() && (file.chunk_ids.len() as u64) < file.number_of_chunks {
+            incomplete_files.push(file.id);
+        }
+        indexed_files.push(file.clone());
+    });
+
+    incomplete_files.into_iter().for_each(insert_pending_upload);
+    indexed_files.iter().for_each(|file| {
+        insert_owner_index(file.owner, file.id);
+        insert_created_at_index(file);
+        index_file(file);
     });
+}
+
+pub fn get_pending_upload_ids() -> Vec<FileID> {
+    PENDING_UPLOADS.with(|p| p.borrow().iter().map(|(file_id, _)| file_id).collect())
+}
+
+fn insert_pending_upload(file_id: FileID) {
+    let _ = PENDING_UPLOADS.with(|p| p.borrow_mut().insert(file_id, ()));
+}
+
+pub fn remove_pending_upload(file_id: &FileID) {
+    PENDING_UPLOADS.with(|p| p.borrow_mut().remove(file_id));
+}
 
-    all_files
+fn next_file_id() -> FileID {
+    CURRENT_FILE_ID.with(|current_id| {
+        let id = *current_id.borrow().get();
+        current_id
+            .borrow_mut()
+            .set(id + 1)
+            .expect("Failed to save the file ID counter");
+        id
+    })
 }
 
 pub fn get_current_file_id() -> u64 {
-    CURRENT_FILE_ID.with(|current_id| *current_id.borrow_mut())
+    CURRENT_FILE_ID.with(|current_id| *current_id.borrow().get())
 }
 
-pub fn get_file_by_id(file_id: &FileID) -> Result<File, String> {
+pub fn set_current_file_id(id: FileID) {
+    CURRENT_FILE_ID.with(|current_id| {
+        current_id
+            .borrow_mut()
+            .set(id)
+            .expect("Failed to save the file ID counter")
+    });
+}
+
+/// Gets a file unless it is in the trash
+pub fn get_file_by_id(file_id: &FileID) -> Result<File, AssetError> {
     match get_file(file_id) {
+        Some(file) if file.is_deleted() => Err(AssetError::FileDeleted),
         Some(file) => Ok(file),
-        None => Err(String::from("file not found")),
+        None => Err(AssetError::FileNotFound),
+    }
+}
+
+/// Gets a file only once its upload has been committed, and unless it is in the trash
+pub fn get_committed_file_by_id(file_id: &FileID) -> Result<File, AssetError> {
+    match get_file(file_id) {
+        Some(file) if file.is_deleted() => Err(AssetError::FileDeleted),
+        Some(file) if file.is_committed() => Ok(file),
+        _ => Err(AssetError::FileNotFound),
     }
 }
 
 pub fn get_file(key: &FileID) -> Option<File> {
-    FILE_MAP.with(|p| p.borrow().get(&key))
+    FILE_MAP.with(|p| p.borrow().get(key))
 }
 
 pub fn insert_file(key: FileID, value: File) -> Result<Option<File>, InsertError> {
     FILE_MAP.with(|p| p.borrow_mut().insert(key, value))
 }
 
+/// Deleting a file only marks it, see `delete_file`, this is for when it is purged
 pub fn remove_file(key: &FileID) -> Option<File> {
-    // TODO: instead of removing this, let's set soft-delete to true instead
     FILE_MAP.with(|p| p.borrow_mut().remove(key))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::models::file::tests::test_file;
+
+    fn chunk(order_id: u64, data: &[u8]) -> FileChunk {
+        FileChunk {
+            id: order_id,
+            file_id: 1,
+            order_id,
+            chunk_data: ByteBuf::from(data.to_vec()),
+            metadata: String::new(),
+            deleted_at: None,
+            created_at: 0,
+            updated_at: 0,
+            hash: Sha3_256::digest(data).into(),
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn upload_hashes_the_chunks_in_order() {
+        let chunks = vec![chunk(1, b"world"), chunk(0, b"hello ")];
+        let expected_sha256: Hash = Sha256::digest(b"hello world").into();
+
+        let (hash, sha256, file_size) = hash_upload(2, 6, chunks, expected_sha256).unwrap();
+        assert_eq!(hash, <Hash>::from(Sha3_256::digest(b"hello world")));
+        assert_eq!(sha256, expected_sha256);
+        assert_eq!(file_size, 11);
+    }
+
+    #[test]
+    fn upload_with_the_wrong_hash_is_refused() {
+        let chunks = vec![chunk(0, b"hello "), chunk(1, b"world")];
+        let expected_sha256: Hash = Sha256::digest(b"world hello ").into();
+
+        assert!(matches!(
+            hash_upload(2, 6, chunks, expected_sha256),
+            Err(AssetError::HashMismatch)
+        ));
+    }
+
+    #[test]
+    fn upload_with_a_short_chunk_before_the_last_is_refused() {
+        let chunks = vec![chunk(0, b"hello "), chunk(1, b"big"), chunk(2, b" world")];
+        let expected_sha256: Hash = Sha256::digest(b"hello big world").into();
+
+        assert!(matches!(
+            hash_upload(3, 6, chunks, expected_sha256),
+            Err(AssetError::ChunkTooSmall { chunk_size: 6 })
+        ));
+    }
+
+    #[test]
+    fn upload_missing_chunks_is_refused() {
+        let expected_sha256: Hash = Sha256::digest(b"hello world").into();
+
+        let missing_last = vec![chunk(0, b"hello ")];
+        assert!(matches!(
+            hash_upload(2, 6, missing_last, expected_sha256),
+            Err(AssetError::MissingChunks)
+        ));
+        let missing_first = vec![chunk(1, b"world"), chunk(2, b"")];
+        assert!(matches!(
+            hash_upload(2, 6, missing_first, expected_sha256),
+            Err(AssetError::MissingChunks)
+        ));
+    }
+
+    fn deleted_file(id: FileID, deleted_at: u64) -> File {
+        File {
+            deleted_at: Some(deleted_at),
+            ..test_file(id, Principal::anonymous())
+        }
+    }
+
+    #[test]
+    fn trash_keys_sort_by_deletion_time() {
+        let key = trash_index_key(&deleted_file(300, 5)).unwrap();
+        assert_eq!(deleted_at_from_trash_key(&key), 5);
+        assert_eq!(file_id_from_index_key(&key), 300);
+        assert!(key < trash_index_key(&deleted_file(1, 256)).unwrap());
+        assert_eq!(trash_index_key(&test_file(1, Principal::anonymous())), None);
+    }
+
+    #[test]
+    fn trashed_file_can_be_restored_until_it_expires() {
+        let file = deleted_file(1, 100);
+        let expires_at = 100 + TRASH_RETENTION;
+        assert_eq!(trash_expires_at(&file), expires_at);
+
+        assert!(check_restorable(&file, expires_at - 1).is_ok());
+        assert!(matches!(
+            check_restorable(&file, expires_at),
+            Err(AssetError::FileNotFound)
+        ));
+        assert!(matches!(
+            check_restorable(&test_file(2, Principal::anonymous()), 0),
+            Err(AssetError::NotInTrash)
+        ));
+    }
+
+    #[test]
+    fn only_expired_trash_is_purged() {
+        [(1, 300), (2, 100), (3, 200)]
+            .into_iter()
+            .for_each(|(id, deleted_at)| insert_trash_index(&deleted_file(id, deleted_at)));
+
+        let expired: Vec<FileID> = get_expired_trash_keys(250, 10)
+            .iter()
+            .map(|key| file_id_from_index_key(key))
+            .collect();
+        assert_eq!(expired, vec![2, 3]);
+        assert_eq!(get_expired_trash_keys(250, 1).len(), 1);
+        assert!(get_expired_trash_keys(100, 10).is_empty());
+    }
+
+    #[test]
+    fn files_can_be_visited_in_batches() {
+        [1, 2, 256, 257].into_iter().for_each(|id| {
+            insert_file(id, test_file(id, Principal::anonymous())).unwrap();
+        });
+
+        let mut visited: Vec<FileID> = vec![];
+        let mut cursor = None;
+        loop {
+            let mut batch = 0;
+            cursor = for_each_file_from(cursor, |file| {
+                batch += 1;
+                if batch > 3 {
+                    return false;
+                }
+                visited.push(file.id);
+                true
+            });
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        visited.sort_unstable();
+        assert_eq!(visited, vec![1, 2, 256, 257]);
+    }
+}
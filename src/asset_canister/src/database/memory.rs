@@ -0,0 +1,190 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::cell::Cell as StableCell;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::DefaultMemoryImpl;
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Stable memory layout
+//
+// | ID  | Store                               | Module                |
+// |-----|-------------------------------------|-----------------------|
+// | 0   | chunks                              | database::chunks      |
+// | 1   | files                               | database::file        |
+// | 2   | pending uploads index               | database::file        |
+// | 3   | owner index                         | database::file        |
+// | 4   | users                               | database::users       |
+// | 5   | blocked users                       | auth::moderation      |
+// | 6   | current file ID                     | database::file        |
+// | 7   | current chunk ID                    | database::chunks      |
+// | 8   | deletion jobs                       | jobs::deletion        |
+// | 9   | created at index                    | database::file        |
+// | 10  | roles                               | database::roles       |
+// | 11  | share link secret                   | auth::share_link      |
+// | 12  | config                              | database::config      |
+// | 13  | warnings                            | auth::warnings        |
+// | 14  | appeals                             | auth::appeals         |
+// | 15  | audit log index                     | database::audit       |
+// | 16  | audit log entries                   | database::audit       |
+// | 17  | chunk hash index                    | database::chunks      |
+// | 18  | chunk references                    | database::chunks      |
+// | 19  | trash index                         | database::file        |
+// | 20  | file versions                       | database::versions    |
+// | 21  | search index                        | database::search      |
+// | 22  | schema version                      | database::migration   |
+// | 23  | running deletion jobs index         | jobs::deletion        |
+// | 24  | warnings by time index              | auth::warnings        |
+// | 254 | this layout, for the startup check  | database::memory      |
+//
+// Each stable structure gets its own memory, these IDs must never change
+// or the structures will read each other's data
+// New stores take the next free ID and are added to `MEMORY_LAYOUT`
+const CHUNKS: u8 = 0;
+const FILES: u8 = 1;
+const PENDING_UPLOADS: u8 = 2;
+const OWNER_INDEX: u8 = 3;
+const USERS: u8 = 4;
+const BLOCKED: u8 = 5;
+const CURRENT_FILE_ID: u8 = 6;
+const CURRENT_CHUNK_ID: u8 = 7;
+const DELETION_JOBS: u8 = 8;
+const CREATED_AT_INDEX: u8 = 9;
+const ROLES: u8 = 10;
+const SHARE_LINK_SECRET: u8 = 11;
+const CONFIG: u8 = 12;
+const WARNINGS: u8 = 13;
+const APPEALS: u8 = 14;
+const AUDIT_LOG_INDEX: u8 = 15;
+const AUDIT_LOG_DATA: u8 = 16;
+const CHUNK_HASH_INDEX: u8 = 17;
+const CHUNK_REFERENCES: u8 = 18;
+const TRASH_INDEX: u8 = 19;
+const FILE_VERSIONS: u8 = 20;
+const SEARCH_INDEX: u8 = 21;
+const SCHEMA_VERSION: u8 = 22;
+const RUNNING_DELETION_JOBS: u8 = 23;
+const WARNINGS_BY_TIME: u8 = 24;
+const LAYOUT: u8 = 254;
+
+pub const CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(CHUNKS);
+pub const FILES_MEMORY_ID: MemoryId = MemoryId::new(FILES);
+pub const PENDING_UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(PENDING_UPLOADS);
+pub const OWNER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(OWNER_INDEX);
+pub const USERS_MEMORY_ID: MemoryId = MemoryId::new(USERS);
+pub const BLOCKED_MEMORY_ID: MemoryId = MemoryId::new(BLOCKED);
+pub const CURRENT_FILE_ID_MEMORY_ID: MemoryId = MemoryId::new(CURRENT_FILE_ID);
+pub const CURRENT_CHUNK_ID_MEMORY_ID: MemoryId = MemoryId::new(CURRENT_CHUNK_ID);
+pub const DELETION_JOBS_MEMORY_ID: MemoryId = MemoryId::new(DELETION_JOBS);
+pub const CREATED_AT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(CREATED_AT_INDEX);
+pub const ROLES_MEMORY_ID: MemoryId = MemoryId::new(ROLES);
+pub const SHARE_LINK_SECRET_MEMORY_ID: MemoryId = MemoryId::new(SHARE_LINK_SECRET);
+pub const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(CONFIG);
+pub const WARNINGS_MEMORY_ID: MemoryId = MemoryId::new(WARNINGS);
+pub const APPEALS_MEMORY_ID: MemoryId = MemoryId::new(APPEALS);
+pub const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(AUDIT_LOG_INDEX);
+pub const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(AUDIT_LOG_DATA);
+pub const CHUNK_HASH_INDEX_MEMORY_ID: MemoryId = MemoryId::new(CHUNK_HASH_INDEX);
+pub const CHUNK_REFERENCES_MEMORY_ID: MemoryId = MemoryId::new(CHUNK_REFERENCES);
+pub const TRASH_INDEX_MEMORY_ID: MemoryId = MemoryId::new(TRASH_INDEX);
+pub const FILE_VERSIONS_MEMORY_ID: MemoryId = MemoryId::new(FILE_VERSIONS);
+pub const SEARCH_INDEX_MEMORY_ID: MemoryId = MemoryId::new(SEARCH_INDEX);
+pub const SCHEMA_VERSION_MEMORY_ID: MemoryId = MemoryId::new(SCHEMA_VERSION);
+pub const RUNNING_DELETION_JOBS_MEMORY_ID: MemoryId = MemoryId::new(RUNNING_DELETION_JOBS);
+pub const WARNINGS_BY_TIME_MEMORY_ID: MemoryId = MemoryId::new(WARNINGS_BY_TIME);
+const LAYOUT_MEMORY_ID: MemoryId = MemoryId::new(LAYOUT);
+
+/// The store each memory ID is allocated to, saved so a later version can check it agrees
+const MEMORY_LAYOUT: &[(u8, &str)] = &[
+    (CHUNKS, "chunks"),
+    (FILES, "files"),
+    (PENDING_UPLOADS, "pending_uploads"),
+    (OWNER_INDEX, "owner_index"),
+    (USERS, "users"),
+    (BLOCKED, "blocked"),
+    (CURRENT_FILE_ID, "current_file_id"),
+    (CURRENT_CHUNK_ID, "current_chunk_id"),
+    (DELETION_JOBS, "deletion_jobs"),
+    (CREATED_AT_INDEX, "created_at_index"),
+    (ROLES, "roles"),
+    (SHARE_LINK_SECRET, "share_link_secret"),
+    (CONFIG, "config"),
+    (WARNINGS, "warnings"),
+    (APPEALS, "appeals"),
+    (AUDIT_LOG_INDEX, "audit_log_index"),
+    (AUDIT_LOG_DATA, "audit_log_data"),
+    (CHUNK_HASH_INDEX, "chunk_hash_index"),
+    (CHUNK_REFERENCES, "chunk_references"),
+    (TRASH_INDEX, "trash_index"),
+    (FILE_VERSIONS, "file_versions"),
+    (SEARCH_INDEX, "search_index"),
+    (SCHEMA_VERSION, "schema_version"),
+    (RUNNING_DELETION_JOBS, "running_deletion_jobs"),
+    (WARNINGS_BY_TIME, "warnings_by_time"),
+];
+
+thread_local! {
+    // The memory manager is used for simulating multiple memories. Given a `MemoryId` it can
+    // return a memory that can be used by stable structures.
+    // There must only be one manager for the canister, since each one keeps its own copy of
+    // which parts of stable memory are allocated
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // Candid encoded `Vec<(u8, String)>`, empty until the layout is first saved
+    static SAVED_LAYOUT: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(get_memory(LAYOUT_MEMORY_ID), vec![])
+            .expect("Failed to initialise the memory layout")
+    );
+}
+
+pub fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Traps if the saved memory layout doesn't match `MEMORY_LAYOUT`, otherwise saves it
+/// Stores may be added, but a saved ID must keep the same store
+pub fn check_memory_layout() {
+    let mut ids: Vec<u8> = MEMORY_LAYOUT.iter().map(|(id, _)| *id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.len() != MEMORY_LAYOUT.len() || ids.contains(&LAYOUT) {
+        ic_cdk::trap("Memory layout assigns the same memory ID to more than one store");
+    }
+
+    let saved_layout = SAVED_LAYOUT.with(|layout| layout.borrow().get().clone());
+    if !saved_layout.is_empty() {
+        let saved_layout = match Decode!(&saved_layout, Vec<(u8, String)>) {
+            Ok(saved_layout) => saved_layout,
+            Err(_) => ic_cdk::trap("Saved memory layout could not be decoded"),
+        };
+
+        saved_layout.iter().for_each(|(id, saved_store)| {
+            match MEMORY_LAYOUT
+                .iter()
+                .find(|(current_id, _)| current_id == id)
+            {
+                Some((_, store)) if store == saved_store => (),
+                Some((_, store)) => ic_cdk::trap(&format!(
+                    "Memory layout mismatch: memory {} holds {} but is assigned to {}",
+                    id, saved_store, store
+                )),
+                None => ic_cdk::trap(&format!(
+                    "Memory layout mismatch: memory {} holds {} but is no longer assigned",
+                    id, saved_store
+                )),
+            }
+        });
+    }
+
+    let layout: Vec<(u8, String)> = MEMORY_LAYOUT
+        .iter()
+        .map(|(id, store)| (*id, String::from(*store)))
+        .collect();
+    SAVED_LAYOUT.with(|saved_layout| {
+        saved_layout
+            .borrow_mut()
+            .set(Encode!(&layout).unwrap())
+            .expect("Failed to save the memory layout")
+    });
+}
@@ -0,0 +1,248 @@
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::cell::Cell as StableCell;
+use ic_stable_structures::{DefaultMemoryImpl, Memory};
+use std::{cell::RefCell, collections::HashMap};
+
+use crate::auth::moderation::{insert_blocked, Blocked};
+
+use super::chunks::{set_current_chunk_id, ChunkID};
+use super::file::{index_files, set_current_file_id, FileID};
+use super::memory::{get_memory, Memory as VirtualMemory, SCHEMA_VERSION_MEMORY_ID};
+use super::users::{insert_user_info, UserInfo};
+
+/// The memory manager's header: magic, version, bucket count, bucket size,
+/// 32 reserved bytes and the size of each of the 255 memories
+/// Its bucket table, one byte per bucket, starts straight after
+const MEMORY_MANAGER_HEADER_SIZE: usize = 3 + 1 + 2 + 2 + 32 + 255 * 8;
+/// The buckets themselves start at the second WASM page
+const BUCKETS_OFFSET_IN_BYTES: usize = 65536;
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+const MEMORY_MANAGER_LAYOUT_VERSION: u8 = 1;
+const BUCKET_SIZE_IN_PAGES: u16 = 1024;
+const MAX_NUM_BUCKETS: usize = 32768;
+const UNALLOCATED_BUCKET_MARKER: u8 = 255;
+
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+/// Bumped along with a new migration whenever stored data has to be rewritten
+const SCHEMA_VERSION: u64 = 2;
+/// Files are indexed, see `index_files`
+const INDEXED_SCHEMA_VERSION: u64 = 1;
+/// Every committed file has its SHA-256, which `jobs::certification` works out for older files
+pub const HASHED_SCHEMA_VERSION: u64 = 2;
+
+thread_local! {
+    // Zero for canisters upgraded from a version that didn't store it
+    static STORED_SCHEMA_VERSION: RefCell<StableCell<u64, VirtualMemory>> = RefCell::new(
+        StableCell::init(get_memory(SCHEMA_VERSION_MEMORY_ID), 0)
+            .expect("Failed to initialise the schema version")
+    );
+}
+
+/// The state older versions of the canister serialised in `pre_upgrade`
+/// Users used to carry a `files_owned` set, which Candid skips when decoding into `UserInfo`
+#[derive(CandidType, Deserialize)]
+struct LegacyStableState {
+    users: HashMap<Principal, UserInfo>,
+    current_file_id: FileID,
+    current_chunk_id: ChunkID,
+    blocked: HashMap<Principal, Blocked>,
+}
+
+/// Moves state saved by older versions of the canister into the stable structures
+/// Must run before any stable structure is touched, since the memory manager
+/// resets stable memory when it doesn't find its header
+pub fn migrate_legacy_state() {
+    let state = match restore_legacy_memory(&DefaultMemoryImpl::default()) {
+        Ok(Some(state)) => state,
+        Ok(None) => return,
+        Err(message) => ic_cdk::trap(message),
+    };
+
+    state.users.into_iter().for_each(|(principal, user_info)| {
+        let _ = insert_user_info(principal, user_info);
+    });
+    state.blocked.into_values().for_each(insert_blocked);
+    set_current_file_id(state.current_file_id);
+    set_current_chunk_id(state.current_chunk_id);
+}
+
+/// A new canister has no data to migrate, so it starts at the current schema version
+pub fn init_schema_version() {
+    set_schema_version(SCHEMA_VERSION);
+}
+
+/// Runs each one-off migration of stored data that is small enough to finish within the upgrade
+/// Longer ones are left to heartbeat jobs, which move the schema version on once they are done
+pub fn run_schema_migrations() {
+    if get_schema_version() < INDEXED_SCHEMA_VERSION {
+        index_files();
+        set_schema_version(INDEXED_SCHEMA_VERSION);
+    }
+}
+
+pub fn get_schema_version() -> u64 {
+    STORED_SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+pub fn set_schema_version(schema_version: u64) {
+    STORED_SCHEMA_VERSION.with(|version| {
+        version
+            .borrow_mut()
+            .set(schema_version)
+            .expect("Failed to save the schema version")
+    });
+}
+
+/// Decodes the legacy state and gives the memory manager its header back
+/// Returns None if the memory doesn't hold a legacy state
+///
+/// The old `pre_upgrade` wrote its state from the start of stable memory, over the memory manager's
+/// header. The bucket table follows the header, so only a state that fits in the header left it,
+/// and with it the files, intact
+fn restore_legacy_memory(memory: &impl Memory) -> Result<Option<LegacyStableState>, &'static str> {
+    if memory.size() == 0 {
+        return Ok(None);
+    }
+
+    let mut magic = [0; 4];
+    memory.read(0, &mut magic);
+    if &magic != CANDID_MAGIC {
+        return Ok(None);
+    }
+
+    // The state can't have been written past the first bucket, where the files begin
+    let mut bytes = vec![0; BUCKETS_OFFSET_IN_BYTES];
+    memory.read(0, &mut bytes);
+    let state = match decode_legacy_state(&bytes[..MEMORY_MANAGER_HEADER_SIZE]) {
+        Some(state) => state,
+        None => match decode_legacy_state(&bytes) {
+            Some(_) => return Err(
+                "Legacy state overwrote the memory manager's bucket table and can't be migrated",
+            ),
+            None => return Err("Legacy state could not be decoded"),
+        },
+    };
+
+    restore_memory_manager_header(memory);
+    Ok(Some(state))
+}
+
+fn decode_legacy_state(bytes: &[u8]) -> Option<LegacyStableState> {
+    let mut deserializer = IDLDeserialize::new(bytes).ok()?;
+    deserializer.get_value::<LegacyStableState>().ok()
+}
+
+/// Rebuilds the memory manager's header from its bucket table, which follows the header
+/// Memory sizes are rounded up to whole buckets, which the stable structures don't rely on
+fn restore_memory_manager_header(memory: &impl Memory) {
+    let mut buckets = vec![0; MAX_NUM_BUCKETS];
+    memory.read(MEMORY_MANAGER_HEADER_SIZE as u64, &mut buckets);
+
+    let mut memory_sizes_in_pages = [0u64; 255];
+    let mut allocated_buckets: u16 = 0;
+    buckets
+        .iter()
+        .filter(|memory_id| **memory_id != UNALLOCATED_BUCKET_MARKER)
+        .for_each(|memory_id| {
+            memory_sizes_in_pages[*memory_id as usize] += BUCKET_SIZE_IN_PAGES as u64;
+            allocated_buckets += 1;
+        });
+
+    let mut header = Vec::with_capacity(MEMORY_MANAGER_HEADER_SIZE);
+    header.extend_from_slice(MEMORY_MANAGER_MAGIC);
+    header.push(MEMORY_MANAGER_LAYOUT_VERSION);
+    header.extend_from_slice(&allocated_buckets.to_le_bytes());
+    header.extend_from_slice(&BUCKET_SIZE_IN_PAGES.to_le_bytes());
+    header.extend_from_slice(&[0; 32]);
+    memory_sizes_in_pages
+        .iter()
+        .for_each(|size| header.extend_from_slice(&size.to_le_bytes()));
+
+    memory.write(0, &header);
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Encode;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::{StableBTreeMap, VectorMemory};
+
+    use super::*;
+
+    fn legacy_state(number_blocked: u8) -> LegacyStableState {
+        LegacyStableState {
+            users: HashMap::new(),
+            current_file_id: 7,
+            current_chunk_id: 11,
+            blocked: (0..number_blocked)
+                .map(|index| {
+                    let principal = Principal::from_slice(&[index; 29]);
+                    let blocked = Blocked {
+                        principal,
+                        metadata: String::from("Passed the warning limit"),
+                    };
+                    (principal, blocked)
+                })
+                .collect(),
+        }
+    }
+
+    // Two maps whose buckets are allocated in the opposite order to their memory IDs
+    fn memory_with_maps() -> VectorMemory {
+        let memory = VectorMemory::default();
+        let manager = MemoryManager::init(memory.clone());
+        let mut files: StableBTreeMap<_, u64, u64> =
+            StableBTreeMap::init(manager.get(MemoryId::new(1)), 8, 8);
+        let mut chunks: StableBTreeMap<_, u64, u64> =
+            StableBTreeMap::init(manager.get(MemoryId::new(0)), 8, 8);
+        files.insert(2, 20).unwrap();
+        chunks.insert(1, 10).unwrap();
+        memory
+    }
+
+    #[test]
+    fn memory_without_legacy_state_is_left_alone() {
+        assert!(matches!(
+            restore_legacy_memory(&VectorMemory::default()),
+            Ok(None)
+        ));
+        assert!(matches!(
+            restore_legacy_memory(&memory_with_maps()),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn legacy_state_is_migrated_and_maps_are_kept() {
+        let memory = memory_with_maps();
+        memory.write(0, &Encode!(&legacy_state(3)).unwrap());
+
+        let state = restore_legacy_memory(&memory).unwrap().unwrap();
+        assert_eq!(state.current_file_id, 7);
+        assert_eq!(state.current_chunk_id, 11);
+        assert_eq!(state.blocked.len(), 3);
+
+        let manager = MemoryManager::init(memory);
+        let files: StableBTreeMap<_, u64, u64> =
+            StableBTreeMap::init(manager.get(MemoryId::new(1)), 8, 8);
+        let chunks: StableBTreeMap<_, u64, u64> =
+            StableBTreeMap::init(manager.get(MemoryId::new(0)), 8, 8);
+        assert_eq!(files.get(&2), Some(20));
+        assert_eq!(chunks.get(&1), Some(10));
+    }
+
+    #[test]
+    fn legacy_state_over_the_bucket_table_is_refused() {
+        let memory = memory_with_maps();
+        let bytes = Encode!(&legacy_state(100)).unwrap();
+        assert!(bytes.len() > MEMORY_MANAGER_HEADER_SIZE);
+        memory.write(0, &bytes);
+
+        assert_eq!(
+            restore_legacy_memory(&memory).err(),
+            Some("Legacy state overwrote the memory manager's bucket table and can't be migrated")
+        );
+    }
+}
@@ -1,3 +1,12 @@
+pub mod audit;
+pub mod certification;
 pub mod chunks;
+pub mod config;
 pub mod file;
+pub mod memory;
+pub mod migration;
+pub mod roles;
+pub mod search;
+pub mod uploads;
 pub mod users;
+pub mod versions;
@@ -0,0 +1,59 @@
+use candid::{Decode, Encode};
+use ic_cdk::export::Principal;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::models::role::Role;
+
+use super::memory::{get_memory, Memory, ROLES_MEMORY_ID};
+
+// Keys are principals, which are at most 29 bytes
+const MAX_KEY_SIZE: u32 = 29;
+const MAX_VALUE_SIZE: u32 = 32;
+
+impl Storable for Role {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+thread_local! {
+    // Controllers are always owners, so they don't need to be stored here
+    static ROLES: RefCell<StableBTreeMap<Memory, Vec<u8>, Role>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(ROLES_MEMORY_ID),
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+    );
+}
+
+pub fn get_stored_role(principal: Principal) -> Option<Role> {
+    ROLES.with(|roles| roles.borrow().get(&principal.as_slice().to_vec()))
+}
+
+pub fn set_role(principal: Principal, role: Role) {
+    let _ = ROLES.with(|roles| {
+        roles
+            .borrow_mut()
+            .insert(principal.as_slice().to_vec(), role)
+    });
+}
+
+pub fn remove_role(principal: Principal) -> Option<Role> {
+    ROLES.with(|roles| roles.borrow_mut().remove(&principal.as_slice().to_vec()))
+}
+
+pub fn get_roles() -> Vec<(Principal, Role)> {
+    ROLES.with(|roles| {
+        roles
+            .borrow()
+            .iter()
+            .map(|(key, role)| (Principal::from_slice(&key), role))
+            .collect()
+    })
+}
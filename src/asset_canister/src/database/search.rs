@@ -0,0 +1,296 @@
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::{cell::RefCell, collections::BTreeSet};
+
+use crate::api::error::AssetError;
+use crate::models::file::{File, FileType};
+
+use super::file::{get_file, FileID};
+use super::memory::{get_memory, Memory, SEARCH_INDEX_MEMORY_ID};
+
+/// Longer tokens are cut off, so a search only looks at their first 32 bytes
+const MAX_TOKEN_LENGTH: usize = 32;
+// Keys are a token and the file ID
+const MAX_KEY_SIZE: u32 = MAX_TOKEN_LENGTH as u32 + 8;
+const MAX_PAGE_SIZE: usize = 100;
+// The most index entries a search reads, so prefixes that match many tokens can't exhaust the instruction limit
+const MAX_SCANNED_PER_PAGE: usize = 1000;
+const MAX_QUERY_TOKENS: usize = 5;
+
+thread_local! {
+    // Maps each token of a file's name and tags to the file, so files can be found without iterating FILE_MAP
+    // Only committed files outside the trash are indexed
+    static SEARCH_INDEX: RefCell<StableBTreeMap<Memory, Vec<u8>, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(SEARCH_INDEX_MEMORY_ID),
+            MAX_KEY_SIZE,
+            0
+        )
+    );
+}
+
+/// Splits text into lowercase words, anything that isn't a letter or a number separates them
+pub fn tokenise(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let word = word.to_lowercase();
+            let mut end = word.len().min(MAX_TOKEN_LENGTH);
+            while !word.is_char_boundary(end) {
+                end -= 1;
+            }
+            word[..end].to_string()
+        })
+        .collect()
+}
+
+/// The tokens a file can be found by, in order
+fn file_tokens(file: &File) -> BTreeSet<String> {
+    let mut tokens: BTreeSet<String> = tokenise(&file.file_name).into_iter().collect();
+    if let Some(metadata) = &file.file_metadata {
+        metadata
+            .tags
+            .iter()
+            .for_each(|tag| tokens.extend(tokenise(tag)));
+    }
+    tokens
+}
+
+/// Adds a file to the index, unless it is still uploading or in the trash
+pub fn index_file(file: &File) {
+    if !file.is_committed() || file.is_deleted() {
+        return;
+    }
+
+    SEARCH_INDEX.with(|p| {
+        let mut index = p.borrow_mut();
+        file_tokens(file).iter().for_each(|token| {
+            let _ = index.insert(search_key(token, file.id), ());
+        });
+    });
+}
+
+/// Removes a file from the index, this must be called with the file as it was indexed
+pub fn unindex_file(file: &File) {
+    SEARCH_INDEX.with(|p| {
+        let mut index = p.borrow_mut();
+        file_tokens(file).iter().for_each(|token| {
+            index.remove(&search_key(token, file.id));
+        });
+    });
+}
+
+/// Finds files with a word in their name or tags starting with each word of the query
+///
+/// The index is read from the longest word of the query, the rest are checked against each file found,
+/// so files come in order of their word matching the longest one.
+/// At most `MAX_SCANNED_PER_PAGE` index entries are read, so a page can hold fewer than
+/// `limit` files while more remain. The cursor is the index key to carry on from
+pub fn search_files(
+    query: &str,
+    owner: Option<Principal>,
+    file_type: Option<FileType>,
+    can_find: impl Fn(&File) -> bool,
+    cursor: Option<Vec<u8>>,
+    limit: usize,
+) -> Result<(Vec<File>, Option<Vec<u8>>), AssetError> {
+    let mut query_tokens = tokenise(query);
+    if query_tokens.is_empty() || query_tokens.len() > MAX_QUERY_TOKENS {
+        return Err(AssetError::InvalidSearchQuery);
+    }
+    query_tokens.sort_by_key(|token| std::cmp::Reverse(token.len()));
+    let prefix = query_tokens.remove(0);
+
+    let offset = match cursor {
+        Some(cursor) if cursor.starts_with(prefix.as_bytes()) => {
+            Some(cursor[prefix.len()..].to_vec())
+        }
+        Some(_) => return Err(AssetError::InvalidCursor),
+        None => None,
+    };
+
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let mut files: Vec<File> = vec![];
+
+    SEARCH_INDEX.with(|p| {
+        let index = p.borrow();
+        for (scanned, (key, _)) in index.range(prefix.as_bytes().to_vec(), offset).enumerate() {
+            if files.len() >= limit || scanned >= MAX_SCANNED_PER_PAGE {
+                return Ok((files, Some(key)));
+            }
+
+            let (token, file_id) = split_search_key(&key);
+            if let Some(file) = get_file(&file_id) {
+                let tokens = file_tokens(&file);
+                // A file can have several tokens with the prefix, it is only returned at the first
+                let first_match = tokens.iter().find(|t| t.starts_with(&prefix));
+                if first_match.map(|t| t.as_bytes()) == Some(token)
+                    && query_tokens
+                        .iter()
+                        .all(|query_token| tokens.iter().any(|t| t.starts_with(query_token)))
+                    && owner.is_none_or(|owner| file.owner == owner)
+                    && file_type.is_none_or(|file_type| file.file_type == file_type)
+                    && file.is_committed()
+                    && !file.is_deleted()
+                    && can_find(&file)
+                {
+                    files.push(file);
+                }
+            }
+        }
+
+        Ok((files, None))
+    })
+}
+
+fn search_key(token: &str, file_id: FileID) -> Vec<u8> {
+    let mut key = token.as_bytes().to_vec();
+    key.extend_from_slice(&file_id.to_be_bytes());
+    key
+}
+
+fn split_search_key(key: &[u8]) -> (&[u8], FileID) {
+    let (token, file_id) = key.split_at(key.len() - 8);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(file_id);
+    (token, FileID::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::database::file::insert_file;
+    use crate::models::file::{tests::test_file, Visibility};
+    use crate::models::metadata::FileMetadata;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    fn store_test_file(id: FileID, owner: u8, file_name: &str, tags: &[&str]) -> File {
+        let file = File {
+            file_name: String::from(file_name),
+            file_metadata: Some(FileMetadata {
+                tags: tags.iter().map(|tag| String::from(*tag)).collect(),
+                ..Default::default()
+            }),
+            ..test_file(id, principal(owner))
+        };
+        insert_file(id, file.clone()).unwrap();
+        index_file(&file);
+        file
+    }
+
+    fn file_ids(result: Result<(Vec<File>, Option<Vec<u8>>), AssetError>) -> Vec<FileID> {
+        result.unwrap().0.iter().map(|file| file.id).collect()
+    }
+
+    #[test]
+    fn tokenise_splits_lowercase_words() {
+        assert_eq!(
+            tokenise("My Holiday_photo-2024.PNG"),
+            vec!["my", "holiday", "photo", "2024", "png"]
+        );
+        assert!(tokenise(" .-_ ").is_empty());
+    }
+
+    #[test]
+    fn long_tokens_are_cut_on_a_character_boundary() {
+        assert_eq!(tokenise(&"a".repeat(40)), vec!["a".repeat(32)]);
+        let token = format!("a{}", "é".repeat(20));
+        assert_eq!(tokenise(&token), vec![format!("a{}", "é".repeat(15))]);
+    }
+
+    #[test]
+    fn key_holds_the_token_and_file_id() {
+        let key = search_key("beach", 300);
+        assert_eq!(split_search_key(&key), (&b"beach"[..], 300));
+        assert!(search_key("beach", 2) < search_key("beach", 256));
+    }
+
+    #[test]
+    fn every_query_word_has_to_match() {
+        store_test_file(1, 1, "beach sunset.png", &[]);
+        store_test_file(2, 1, "beach party.png", &["Summer"]);
+        store_test_file(3, 2, "beachball.png", &[]);
+
+        let all = |_: &File| true;
+        assert_eq!(
+            file_ids(search_files("BEACH", None, None, all, None, 10)),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            file_ids(search_files("beach sun", None, None, all, None, 10)),
+            vec![1]
+        );
+        assert_eq!(
+            file_ids(search_files("beach sum", None, None, all, None, 10)),
+            vec![2]
+        );
+        assert_eq!(
+            file_ids(search_files(
+                "beach",
+                Some(principal(2)),
+                None,
+                all,
+                None,
+                10
+            )),
+            vec![3]
+        );
+        assert!(matches!(
+            search_files(" - ", None, None, all, None, 10),
+            Err(AssetError::InvalidSearchQuery)
+        ));
+    }
+
+    #[test]
+    fn results_are_filtered_by_visibility_and_state() {
+        store_test_file(1, 1, "holiday.png", &[]);
+        let mut private = store_test_file(2, 1, "holiday.png", &[]);
+        private.visibility = Some(Visibility::Private);
+        insert_file(2, private).unwrap();
+        let mut trashed = store_test_file(3, 1, "holiday.png", &[]);
+        trashed.deleted_at = Some(1);
+        insert_file(3, trashed).unwrap();
+
+        let public = |file: &File| !file.is_private();
+        assert_eq!(
+            file_ids(search_files("holiday", None, None, public, None, 10)),
+            vec![1]
+        );
+        let all = |_: &File| true;
+        assert_eq!(
+            file_ids(search_files("holiday", None, None, all, None, 10)),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn cursor_carries_on_from_the_last_page() {
+        (1..=3).for_each(|id| {
+            store_test_file(id, 1, "page.png", &[]);
+        });
+
+        let all = |_: &File| true;
+        let (files, cursor) = search_files("page", None, None, all, None, 2).unwrap();
+        assert_eq!(
+            files.iter().map(|file| file.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        let cursor = cursor.unwrap();
+
+        let (files, cursor) = search_files("page", None, None, all, Some(cursor), 2).unwrap();
+        assert_eq!(
+            files.iter().map(|file| file.id).collect::<Vec<_>>(),
+            vec![3]
+        );
+        assert_eq!(cursor, None);
+
+        assert!(matches!(
+            search_files("page", None, None, all, Some(search_key("other", 1)), 2),
+            Err(AssetError::InvalidCursor)
+        ));
+    }
+}
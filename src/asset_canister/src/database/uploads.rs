@@ -0,0 +1,58 @@
+use ic_cdk::api::time;
+
+use crate::models::file::File;
+
+use super::config::get_config;
+use super::file::{
+    get_file, get_pending_upload_ids, remove_file_and_chunks, remove_pending_upload, FileID,
+};
+
+/// Uploads that haven't received a chunk for one day (in nano seconds) are abandoned
+pub const UPLOAD_TTL: u64 = 86400000000000;
+
+fn is_incomplete(file: &File) -> bool {
+    !file.is_committed() || (file.chunk_ids.len() as u64) < file.number_of_chunks
+}
+
+fn is_stale(file: &File, now: u64, upload_ttl: u64) -> bool {
+    is_incomplete(file) && now.saturating_sub(file.updated_at) > upload_ttl
+}
+
+/// Gets the incomplete uploads that haven't been updated within the TTL
+pub fn get_stale_uploads() -> Vec<File> {
+    let now = time();
+    let upload_ttl = get_config().upload_ttl;
+
+    get_pending_upload_ids()
+        .into_iter()
+        .filter_map(|file_id| get_file(&file_id))
+        .filter(|file| is_stale(file, now, upload_ttl))
+        .collect()
+}
+
+/// Deletes up to `limit` stale uploads and their chunks, refunding the bytes to their owners
+/// Returns the IDs of the deleted files
+pub fn sweep_stale_uploads(limit: usize) -> Vec<FileID> {
+    let now = time();
+    let upload_ttl = get_config().upload_ttl;
+    let mut swept_files: Vec<FileID> = vec![];
+
+    for file_id in get_pending_upload_ids() {
+        if swept_files.len() >= limit {
+            break;
+        }
+
+        match get_file(&file_id) {
+            Some(file) if is_stale(&file, now, upload_ttl) => {
+                remove_file_and_chunks(&file);
+                swept_files.push(file.id);
+            }
+            // Older files which have since received all of their chunks no longer need tracking
+            Some(file) if !is_incomplete(&file) => remove_pending_upload(&file_id),
+            Some(_) => (),
+            None => remove_pending_upload(&file_id),
+        }
+    }
+
+    swept_files
+}
@@ -1,97 +1,186 @@
+use candid::{Decode, Encode};
 use ic_cdk::export::candid::{CandidType, Deserialize};
 use ic_cdk::export::Principal;
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
 
-use crate::models::file::File;
+use crate::api::error::AssetError;
 
-use super::file::FileID;
+use super::memory::{get_memory, Memory, USERS_MEMORY_ID};
 
+// Keys are principals, which are at most 29 bytes
+const MAX_KEY_SIZE: u32 = 29;
+const MAX_VALUE_SIZE: u32 = 1024;
+
+/// The quota of users without a limit of their own, 500MB
+pub const DEFAULT_BYTE_LIMIT: u64 = 500000000;
+
+// The files a user owns are found through the owner index in `database::file`
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct UserInfo {
     pub blocked: bool,
-    pub files_owned: HashSet<FileID>,
+    // 0 puts the user on the default tier
     pub byte_limit: u64,
+    // Committed files count their size, pending uploads the most they could grow to
     pub bytes_used: u64,
 }
 
-pub type UserStore = HashMap<Principal, UserInfo>;
+impl Storable for UserInfo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
 
 thread_local! {
-    pub static USER_STORE: RefCell<UserStore> = RefCell::default();
+    static USER_STORE: RefCell<StableBTreeMap<Memory, Vec<u8>, UserInfo>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(USERS_MEMORY_ID),
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+    );
 }
 
-pub fn get_user_info(principal: Principal) -> Result<UserInfo, String> {
-    match USER_STORE.with(|user_store| user_store.borrow().get(&principal).cloned()) {
+pub fn get_user_info(principal: Principal) -> Result<UserInfo, AssetError> {
+    match USER_STORE.with(|user_store| user_store.borrow().get(&principal.as_slice().to_vec())) {
         Some(user_info) => Ok(user_info),
-        None => Err(String::from("User not found")),
+        None => Err(AssetError::UserNotFound),
+    }
+}
+
+pub fn insert_user_info(
+    principal: Principal,
+    user_info: UserInfo,
+) -> Result<Principal, AssetError> {
+    match USER_STORE.with(|user_store| {
+        user_store
+            .borrow_mut()
+            .insert(principal.as_slice().to_vec(), user_info)
+    }) {
+        Ok(_) => Ok(principal),
+        Err(e) => Err(AssetError::StorageError(e.to_string())),
     }
 }
 
+/// Records the bytes used by a new file, creating the user if this is their first file
 pub fn update_user_info_file(
     principal: Principal,
-    new_file: &File,
     bytes_used: u64,
-) -> Result<Principal, String> {
+) -> Result<Principal, AssetError> {
     match get_user_info(principal) {
-        Ok(user_info) => match USER_STORE.with(|user_store| {
-            let mut files_owned = user_info.files_owned.clone();
-            files_owned.insert(new_file.id);
-            let new_bytes_used = user_info.bytes_used + bytes_used;
-
-            user_store.borrow_mut().insert(
-                principal,
-                UserInfo {
-                    files_owned,
-                    blocked: user_info.blocked,
-                    byte_limit: 0,
-                    bytes_used: new_bytes_used,
-                },
-            )
-        }) {
-            Some(_) => Ok(principal),
-            None => Ok(principal),
-        },
-        Err(_) => match USER_STORE.with(|user_store| {
-            let mut files_owned = HashSet::new();
-            files_owned.insert(new_file.id);
-            user_store.borrow_mut().insert(
-                principal,
-                UserInfo {
-                    files_owned,
-                    blocked: false,
-                    byte_limit: 0,
-                    bytes_used,
-                },
-            )
-        }) {
-            Some(_) => Ok(principal),
-            None => Ok(principal),
-        },
+        Ok(user_info) => insert_user_info(
+            principal,
+            UserInfo {
+                bytes_used: user_info.bytes_used + bytes_used,
+                ..user_info
+            },
+        ),
+        Err(_) => insert_user_info(
+            principal,
+            UserInfo {
+                blocked: false,
+                byte_limit: 0,
+                bytes_used,
+            },
+        ),
     }
 }
 
-pub fn update_user_info_chunk(principal: Principal, bytes_used: u64) -> Result<Principal, String> {
+/// Gives back bytes no longer used, from a deleted file or an upload that came in under its reservation
+pub fn refund_user_bytes(principal: Principal, bytes_freed: u64) -> Result<Principal, AssetError> {
     match get_user_info(principal) {
-        Ok(user_info) => {
-            match USER_STORE.with(|user_store| {
-                let new_bytes_used = user_info.bytes_used + bytes_used;
-
-                user_store.borrow_mut().insert(
-                    principal,
-                    UserInfo {
-                        files_owned: user_info.files_owned,
-                        blocked: user_info.blocked,
-                        byte_limit: 0,
-                        bytes_used: new_bytes_used,
-                    },
-                )
-            }) {
-                Some(_) => Ok(principal),
-                None => Ok(principal),
-            }
-        }
-
+        Ok(user_info) => insert_user_info(
+            principal,
+            UserInfo {
+                bytes_used: user_info.bytes_used.saturating_sub(bytes_freed),
+                ..user_info
+            },
+        ),
         Err(e) => Err(e),
     }
 }
+
+/// The quota that applies to the user
+pub fn get_byte_limit(user_info: &UserInfo) -> u64 {
+    match user_info.byte_limit {
+        0 => DEFAULT_BYTE_LIMIT,
+        byte_limit => byte_limit,
+    }
+}
+
+/// Gets the bytes used by and the quota of a user, who may not have uploaded anything yet
+pub fn get_usage(principal: Principal) -> (u64, u64) {
+    match get_user_info(principal) {
+        Ok(user_info) => (user_info.bytes_used, get_byte_limit(&user_info)),
+        Err(_) => (0, DEFAULT_BYTE_LIMIT),
+    }
+}
+
+/// Sets the quota of a user, None moves them back to the default tier
+pub fn set_byte_limit(
+    principal: Principal,
+    byte_limit: Option<u64>,
+) -> Result<Principal, AssetError> {
+    let user_info = get_user_info(principal).unwrap_or(UserInfo {
+        blocked: false,
+        byte_limit: 0,
+        bytes_used: 0,
+    });
+
+    insert_user_info(
+        principal,
+        UserInfo {
+            byte_limit: byte_limit.unwrap_or(0),
+            ..user_info
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::database::file::reserved_bytes;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn new_users_are_on_the_default_quota() {
+        assert_eq!(get_usage(principal(1)), (0, DEFAULT_BYTE_LIMIT));
+
+        set_byte_limit(principal(1), Some(1000)).unwrap();
+        assert_eq!(get_usage(principal(1)), (0, 1000));
+        set_byte_limit(principal(1), None).unwrap();
+        assert_eq!(get_usage(principal(1)), (0, DEFAULT_BYTE_LIMIT));
+    }
+
+    #[test]
+    fn reservation_is_swapped_for_the_file_size() {
+        let reserved = reserved_bytes(3, 100);
+        assert_eq!(reserved, 300);
+        update_user_info_file(principal(2), reserved).unwrap();
+        assert_eq!(get_usage(principal(2)).0, 300);
+
+        // The committed file came in at 250 bytes
+        refund_user_bytes(principal(2), reserved - 250).unwrap();
+        assert_eq!(get_usage(principal(2)).0, 250);
+
+        refund_user_bytes(principal(2), 1000).unwrap();
+        assert_eq!(get_usage(principal(2)).0, 0);
+    }
+
+    #[test]
+    fn reservations_saturate() {
+        assert_eq!(reserved_bytes(u64::MAX, 2), u64::MAX);
+        assert!(matches!(
+            refund_user_bytes(principal(3), 10),
+            Err(AssetError::UserNotFound)
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::collections::HashSet;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::api::error::AssetError;
+use crate::models::file::{File, FileVersion};
+
+use super::chunks::{get_chunk_order_ids, get_content_size, release_chunk, ChunkID};
+use super::config::get_config;
+use super::file::FileID;
+use super::memory::{get_memory, Memory, FILE_VERSIONS_MEMORY_ID};
+use super::users::refund_user_bytes;
+
+// Keys are the file ID and the version
+const MAX_KEY_SIZE: u32 = 16;
+// Room for the order and chunk IDs of a little over 6000 chunks
+const MAX_VALUE_SIZE: u32 = 100000;
+
+/// Ten earlier versions of each file are kept by default
+pub const VERSIONS_KEPT: u64 = 10;
+
+impl Storable for FileVersion {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+thread_local! {
+    // The earlier versions of each file, the current version is the file itself
+    static FILE_VERSIONS: RefCell<StableBTreeMap<Memory, Vec<u8>, FileVersion>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(FILE_VERSIONS_MEMORY_ID),
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+    );
+}
+
+/// Keeps the file's current content as an earlier version before it is replaced
+pub fn insert_version(file: &File) -> Result<FileVersion, AssetError> {
+    let version = FileVersion {
+        version: file.get_version(),
+        file_type: file.file_type,
+        chunk_ids: file.chunk_ids.clone(),
+        chunk_order_ids: get_chunk_order_ids(file),
+        number_of_chunks: file.number_of_chunks,
+        chunk_size: file.get_chunk_size(),
+        size: get_content_size(file),
+        hash: file.hash,
+        sha256: file.sha256,
+        replaced_at: time(),
+    };
+
+    match FILE_VERSIONS.with(|p| {
+        p.borrow_mut()
+            .insert(version_key(file.id, version.version), version.clone())
+    }) {
+        Ok(_) => Ok(version),
+        Err(e) => Err(AssetError::StorageError(e.to_string())),
+    }
+}
+
+pub fn get_version(file_id: FileID, version: u64) -> Option<FileVersion> {
+    FILE_VERSIONS.with(|p| p.borrow().get(&version_key(file_id, version)))
+}
+
+/// Gets the earlier versions of a file, oldest first
+pub fn get_versions(file_id: FileID) -> Vec<FileVersion> {
+    FILE_VERSIONS.with(|p| {
+        p.borrow()
+            .range(file_id.to_be_bytes().to_vec(), None)
+            .map(|(_, version)| version)
+            .collect()
+    })
+}
+
+/// Removes the oldest versions of the file beyond the number the config keeps, refunding the owner
+/// Chunks the file or its remaining versions still use are kept
+pub fn prune_versions(file: &File) {
+    let versions = get_versions(file.id);
+    let number_to_remove = versions
+        .len()
+        .saturating_sub(get_config().versions_kept as usize);
+    if number_to_remove == 0 {
+        return;
+    }
+    let (removed_versions, kept_versions) = versions.split_at(number_to_remove);
+
+    let mut chunks_in_use: HashSet<ChunkID> = file.chunk_ids.iter().copied().collect();
+    kept_versions
+        .iter()
+        .for_each(|version| chunks_in_use.extend(&version.chunk_ids));
+
+    removed_versions.iter().for_each(|version| {
+        remove_version(file.id, version.version);
+        version
+            .chunk_ids
+            .iter()
+            .filter(|chunk_id| !chunks_in_use.contains(chunk_id))
+            .for_each(|chunk_id| {
+                release_chunk(*chunk_id, file.id);
+            });
+        let _ = refund_user_bytes(file.owner, version.size);
+    });
+}
+
+/// Removes every earlier version of a file without touching their chunks
+/// Returns the removed versions, so their chunks can be released along with the file's own
+pub fn remove_versions(file_id: FileID) -> Vec<FileVersion> {
+    let versions = get_versions(file_id);
+    versions
+        .iter()
+        .for_each(|version| remove_version(file_id, version.version));
+    versions
+}
+
+fn remove_version(file_id: FileID, version: u64) {
+    FILE_VERSIONS.with(|p| p.borrow_mut().remove(&version_key(file_id, version)));
+}
+
+// Both are big-endian so that each file's versions sort oldest first
+fn version_key(file_id: FileID, version: u64) -> Vec<u8> {
+    let mut key = file_id.to_be_bytes().to_vec();
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::*;
+
+    use crate::database::chunks::{get_chunk_file_ids, move_chunk_references};
+    use crate::models::file::{tests::test_file, FileType};
+
+    fn store_test_version(file_id: FileID, version: u64, chunk_ids: Vec<ChunkID>) {
+        let version = FileVersion {
+            version,
+            file_type: FileType::PNG,
+            chunk_order_ids: (0..chunk_ids.len() as u64).collect(),
+            number_of_chunks: chunk_ids.len() as u64,
+            chunk_ids,
+            chunk_size: 100,
+            size: 100,
+            hash: [0; 32],
+            sha256: None,
+            replaced_at: version,
+        };
+        FILE_VERSIONS.with(|p| {
+            p.borrow_mut()
+                .insert(version_key(file_id, version.version), version)
+                .unwrap()
+        });
+    }
+
+    fn version_numbers(file_id: FileID) -> Vec<u64> {
+        get_versions(file_id)
+            .iter()
+            .map(|version| version.version)
+            .collect()
+    }
+
+    #[test]
+    fn versions_sort_oldest_first_within_each_file() {
+        assert!(version_key(1, 2) < version_key(1, 256));
+        assert!(version_key(1, u64::MAX) < version_key(2, 0));
+
+        [256, 1, 2]
+            .into_iter()
+            .for_each(|version| store_test_version(1, version, vec![]));
+        store_test_version(2, 1, vec![]);
+        assert_eq!(version_numbers(1), vec![1, 2, 256]);
+        assert_eq!(version_numbers(2), vec![1]);
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_versions_and_their_chunks() {
+        let file = File {
+            chunk_ids: vec![100],
+            version: Some(VERSIONS_KEPT + 3),
+            ..test_file(1, Principal::anonymous())
+        };
+        // The oldest version shares its chunk with the current content
+        store_test_version(1, 1, vec![100]);
+        (2..=VERSIONS_KEPT + 2).for_each(|version| store_test_version(1, version, vec![version]));
+        [100, 2]
+            .into_iter()
+            .for_each(|chunk_id| move_chunk_references(&[chunk_id], 0, 1));
+
+        prune_versions(&file);
+
+        assert_eq!(
+            version_numbers(1),
+            (3..=VERSIONS_KEPT + 2).collect::<Vec<u64>>()
+        );
+        assert_eq!(get_chunk_file_ids(100), vec![1]);
+        assert!(get_chunk_file_ids(2).is_empty());
+    }
+}
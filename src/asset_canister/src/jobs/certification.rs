@@ -0,0 +1,45 @@
+use std::cell::RefCell;
+
+use crate::database::certification::hash_legacy_file;
+use crate::database::file::{for_each_file_from, FileID};
+use crate::database::migration::{get_schema_version, set_schema_version, HASHED_SCHEMA_VERSION};
+use crate::models::file::File;
+
+/// Caps how many files are looked at in a single heartbeat
+const SCAN_BATCH_SIZE: usize = 100;
+
+/// Caps how many files are hashed in a single heartbeat, each can be several megabytes
+const HASH_BATCH_SIZE: usize = 2;
+
+thread_local! {
+    // The file to carry on from, it starts over after an upgrade which only repeats the scan
+    static CURSOR: RefCell<Option<FileID>> = RefCell::default();
+}
+
+/// Works out the SHA-256 of the next few files saved before responses were certified
+/// Once every file has been seen the schema version is moved on, so this stops running
+pub fn hash_legacy_files_if_due() {
+    if get_schema_version() >= HASHED_SCHEMA_VERSION {
+        return;
+    }
+
+    let mut scanned = 0;
+    let mut legacy_files: Vec<File> = vec![];
+    let cursor = for_each_file_from(CURSOR.with(|cursor| *cursor.borrow()), |file| {
+        if scanned >= SCAN_BATCH_SIZE || legacy_files.len() >= HASH_BATCH_SIZE {
+            return false;
+        }
+        scanned += 1;
+        if file.is_committed() && file.sha256.is_none() {
+            legacy_files.push(file.clone());
+        }
+        true
+    });
+
+    legacy_files.iter().for_each(hash_legacy_file);
+
+    match cursor {
+        Some(_) => CURSOR.with(|current| *current.borrow_mut() = cursor),
+        None => set_schema_version(HASHED_SCHEMA_VERSION),
+    }
+}
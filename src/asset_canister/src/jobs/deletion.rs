@@ -0,0 +1,157 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::database::file::{count_files_by_owner, delete_files_by_owner, get_file_ids_by_owner};
+use crate::database::memory::{
+    get_memory, Memory, DELETION_JOBS_MEMORY_ID, RUNNING_DELETION_JOBS_MEMORY_ID,
+};
+
+/// Caps how many files are deleted in a single heartbeat to stay within the instruction limit
+const DELETION_BATCH_SIZE: usize = 10;
+
+// Keys are principals, which are at most 29 bytes
+const MAX_KEY_SIZE: u32 = 29;
+const MAX_VALUE_SIZE: u32 = 256;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeletionJob {
+    pub principal: Principal,
+    pub files_total: u64,
+    pub files_deleted: u64,
+    pub bytes_freed: u64,
+    pub started_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for DeletionJob {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+thread_local! {
+    static DELETION_JOBS: RefCell<StableBTreeMap<Memory, Vec<u8>, DeletionJob>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(DELETION_JOBS_MEMORY_ID),
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+    );
+
+    // The principals of jobs that haven't completed, so the heartbeat doesn't read finished ones
+    static RUNNING_DELETION_JOBS: RefCell<StableBTreeMap<Memory, Vec<u8>, ()>> = RefCell::new(
+        StableBTreeMap::init(
+            get_memory(RUNNING_DELETION_JOBS_MEMORY_ID),
+            MAX_KEY_SIZE,
+            0
+        )
+    );
+}
+
+/// Queues the deletion of every file owned by the principal
+/// A job which is already running for the principal carries on where it is
+pub fn start_deletion_job(principal: Principal) {
+    if let Some(job) = get_deletion_job(principal) {
+        if job.completed_at.is_none() {
+            return;
+        }
+    }
+
+    insert_deletion_job(DeletionJob {
+        principal,
+        files_total: count_files_by_owner(principal),
+        files_deleted: 0,
+        bytes_freed: 0,
+        started_at: time(),
+        completed_at: None,
+    });
+}
+
+/// Deletes the next batch of files for the first unfinished job
+pub fn run_deletion_jobs() {
+    let job = RUNNING_DELETION_JOBS
+        .with(|running_jobs| running_jobs.borrow().iter().next())
+        .and_then(|(key, _)| DELETION_JOBS.with(|jobs| jobs.borrow().get(&key)));
+
+    if let Some(job) = job {
+        let deleted_files = delete_files_by_owner(job.principal, DELETION_BATCH_SIZE);
+
+        let bytes_freed: u64 = deleted_files.iter().map(|(_, file_bytes)| file_bytes).sum();
+
+        // Stale index entries don't count as deleted files, so check what is left instead
+        let completed_at = match get_file_ids_by_owner(job.principal, Some(1)).is_empty() {
+            true => Some(time()),
+            false => None,
+        };
+
+        insert_deletion_job(DeletionJob {
+            files_deleted: job.files_deleted + deleted_files.len() as u64,
+            bytes_freed: job.bytes_freed + bytes_freed,
+            completed_at,
+            ..job
+        });
+    }
+}
+
+pub fn get_deletion_jobs() -> Vec<DeletionJob> {
+    DELETION_JOBS.with(|jobs| jobs.borrow().iter().map(|(_, job)| job).collect())
+}
+
+pub fn get_deletion_job(principal: Principal) -> Option<DeletionJob> {
+    DELETION_JOBS.with(|jobs| jobs.borrow().get(&principal.as_slice().to_vec()))
+}
+
+fn insert_deletion_job(job: DeletionJob) {
+    let key = job.principal.as_slice().to_vec();
+    RUNNING_DELETION_JOBS.with(|running_jobs| match job.completed_at {
+        Some(_) => {
+            running_jobs.borrow_mut().remove(&key);
+        }
+        None => {
+            let _ = running_jobs.borrow_mut().insert(key.clone(), ());
+        }
+    });
+    let _ = DELETION_JOBS.with(|jobs| jobs.borrow_mut().insert(key, job));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running_job_principals() -> Vec<Principal> {
+        RUNNING_DELETION_JOBS.with(|running_jobs| {
+            running_jobs
+                .borrow()
+                .iter()
+                .map(|(key, _)| Principal::from_slice(&key))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn only_unfinished_jobs_are_indexed() {
+        let job = DeletionJob {
+            principal: Principal::from_slice(&[1; 29]),
+            files_total: 3,
+            files_deleted: 0,
+            bytes_freed: 0,
+            started_at: 0,
+            completed_at: None,
+        };
+        insert_deletion_job(job.clone());
+        assert_eq!(running_job_principals(), vec![job.principal]);
+
+        insert_deletion_job(DeletionJob {
+            completed_at: Some(1),
+            ..job.clone()
+        });
+        assert!(running_job_principals().is_empty());
+        assert_eq!(get_deletion_jobs().len(), 1);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod certification;
+pub mod deletion;
+pub mod ratelimit;
+pub mod trash;
+pub mod uploads;
+pub mod warnings;
@@ -0,0 +1,24 @@
+use ic_cdk::api::time;
+use std::cell::RefCell;
+
+use crate::auth::ratelimit::evict_idle_buckets;
+
+/// Idle rate limit buckets are evicted every 5 minutes (in nano seconds)
+const EVICTION_INTERVAL: u64 = 300000000000;
+
+thread_local! {
+    static LAST_EVICTION: RefCell<u64> = RefCell::default();
+}
+
+/// Evicts idle rate limit buckets if the eviction interval has passed
+pub fn evict_if_due() {
+    let now = time();
+    if now.saturating_sub(LAST_EVICTION.with(|last_eviction| *last_eviction.borrow()))
+        < EVICTION_INTERVAL
+    {
+        return;
+    }
+
+    evict_idle_buckets();
+    LAST_EVICTION.with(|last_eviction| *last_eviction.borrow_mut() = now);
+}
@@ -0,0 +1,27 @@
+use ic_cdk::api::time;
+use std::cell::RefCell;
+
+use crate::database::file::purge_expired_trash;
+
+/// Expired trash is purged once an hour (in nano seconds)
+const PURGE_INTERVAL: u64 = 3600000000000;
+
+/// Caps how many files are purged in a single heartbeat to stay within the instruction limit
+const PURGE_BATCH_SIZE: usize = 20;
+
+thread_local! {
+    static LAST_PURGE: RefCell<u64> = RefCell::default();
+}
+
+/// Permanently removes files whose time in the trash has run out if the purge interval has passed
+/// If a full batch was purged there may be more, so the next heartbeat purges again
+pub fn purge_if_due() {
+    let now = time();
+    if now.saturating_sub(LAST_PURGE.with(|last_purge| *last_purge.borrow())) < PURGE_INTERVAL {
+        return;
+    }
+
+    if purge_expired_trash(PURGE_BATCH_SIZE).len() < PURGE_BATCH_SIZE {
+        LAST_PURGE.with(|last_purge| *last_purge.borrow_mut() = now);
+    }
+}
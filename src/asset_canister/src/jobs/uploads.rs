@@ -0,0 +1,27 @@
+use ic_cdk::api::time;
+use std::cell::RefCell;
+
+use crate::database::uploads::sweep_stale_uploads;
+
+/// Abandoned uploads are swept once an hour (in nano seconds)
+const SWEEP_INTERVAL: u64 = 3600000000000;
+
+/// Caps how many uploads are deleted in a single heartbeat to stay within the instruction limit
+pub const SWEEP_BATCH_SIZE: usize = 20;
+
+thread_local! {
+    static LAST_SWEEP: RefCell<u64> = RefCell::default();
+}
+
+/// Deletes stale uploads if the sweep interval has passed
+/// If a full batch was deleted there may be more, so the next heartbeat sweeps again
+pub fn sweep_if_due() {
+    let now = time();
+    if now.saturating_sub(LAST_SWEEP.with(|last_sweep| *last_sweep.borrow())) < SWEEP_INTERVAL {
+        return;
+    }
+
+    if sweep_stale_uploads(SWEEP_BATCH_SIZE).len() < SWEEP_BATCH_SIZE {
+        LAST_SWEEP.with(|last_sweep| *last_sweep.borrow_mut() = now);
+    }
+}
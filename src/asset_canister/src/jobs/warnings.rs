@@ -0,0 +1,27 @@
+use ic_cdk::api::time;
+use std::cell::RefCell;
+
+use crate::auth::warnings::prune_decayed_warnings;
+
+/// Decayed warnings are pruned once an hour (in nano seconds)
+const PRUNE_INTERVAL: u64 = 3600000000000;
+
+/// Caps how many warnings are removed in a single heartbeat to stay within the instruction limit
+const PRUNE_BATCH_SIZE: usize = 500;
+
+thread_local! {
+    static LAST_PRUNE: RefCell<u64> = RefCell::default();
+}
+
+/// Removes decayed warnings if the prune interval has passed
+/// If a full batch was removed there may be more, so the next heartbeat prunes again
+pub fn prune_if_due() {
+    let now = time();
+    if now.saturating_sub(LAST_PRUNE.with(|last_prune| *last_prune.borrow())) < PRUNE_INTERVAL {
+        return;
+    }
+
+    if prune_decayed_warnings(PRUNE_BATCH_SIZE) < PRUNE_BATCH_SIZE {
+        LAST_PRUNE.with(|last_prune| *last_prune.borrow_mut() = now);
+    }
+}
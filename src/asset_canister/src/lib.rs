@@ -1,79 +1,116 @@
-use auth::moderation::{BlockedStore, BLOCKED_STORE};
-use candid::Deserialize;
-use database::chunks::{ChunkID, CURRENT_CHUNK_ID};
-use database::file::{FileID, CURRENT_FILE_ID};
-use database::users::{UserStore, USER_STORE};
-use ic_cdk::export::candid::CandidType;
+use database::certification::certify_all_files;
+use database::config::set_config;
+use database::memory::check_memory_layout;
+use database::migration::{init_schema_version, migrate_legacy_state, run_schema_migrations};
+use database::roles::{get_stored_role, set_role};
 
-use ic_cdk::storage;
+use candid::candid_method;
 use ic_cdk_macros::*;
-use std::mem;
+
+// Every type in an endpoint signature has to be in scope for `export_service!`
+use api::audit::AuditLogPage;
+use api::canister::{CanisterInfo, InitArgs};
+use api::error::AssetError;
+use api::file::{FEFile, FEFileVersion, FileFilter, FilePage};
+use api::user::{Account, RoleAssignment, UserUsage};
+use auth::appeals::{Appeal, AppealStatus};
+use auth::warnings::Warning;
+use candid::Principal;
+use controllers::http::{HttpRequest, HttpResponse, StreamingCallbackHttpResponse, Token};
+use database::chunks::ChunkID;
+use database::file::FileID;
+use jobs::deletion::DeletionJob;
+use models::config::Config;
+use models::file::{FileChunk, FileType, Visibility};
+use models::metadata::FileMetadata;
+use models::role::Role;
+use serde_bytes::ByteBuf;
 
 mod api;
 mod auth;
 mod controllers;
 mod database;
+mod jobs;
 mod metrics;
 mod models;
 
 #[init]
-fn init() {
+#[candid_method(init)]
+fn init(args: Option<InitArgs>) {
     ic_cdk::setup();
-    CURRENT_FILE_ID.with(|current_id| *current_id.borrow_mut() = 0);
-    CURRENT_CHUNK_ID.with(|current_id| *current_id.borrow_mut() = 0);
+    check_memory_layout();
+    init_schema_version();
+    apply_init_args(args);
+    certify_all_files();
 }
 
-#[derive(Debug, CandidType, Deserialize)]
-pub struct PreStableState {
-    pub users: UserStore,
-    pub current_file_id: FileID,
-    pub current_chunk_id: ChunkID,
-    // moderation
-    pub blocked: BlockedStore,
-}
+// All state lives in stable structures, so there is nothing to save before an upgrade
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    migrate_legacy_state();
+    check_memory_layout();
+    apply_init_args(args);
 
-#[derive(Debug, CandidType, Deserialize)]
-pub struct PostStableState {
-    pub users: UserStore,
-    pub current_file_id: FileID,
-    pub current_chunk_id: ChunkID,
-    // moderation
-    pub blocked: BlockedStore,
+    run_schema_migrations();
+    certify_all_files();
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
-    let users = USER_STORE.with(|state| mem::take(&mut *state.borrow_mut()));
+fn apply_init_args(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        args.admins
+            .into_iter()
+            // Owners passed as admins keep their role
+            .filter(|principal| get_stored_role(*principal) != Some(Role::Owner))
+            .for_each(|principal| set_role(principal, Role::Admin));
 
-    let current_file_id = CURRENT_FILE_ID.with(|state| mem::take(&mut *state.borrow_mut()));
-    let current_chunk_id = CURRENT_CHUNK_ID.with(|state| mem::take(&mut *state.borrow_mut()));
-    // moderation
-    let blocked = BLOCKED_STORE.with(|state| mem::take(&mut *state.borrow_mut()));
+        if let Some(config) = args.config {
+            if let Err(e) = set_config(config) {
+                ic_cdk::trap(&format!("Invalid config: {:?}", e));
+            }
+        }
+    }
+}
 
-    let stable_state = PreStableState {
-        users,
-        current_file_id,
-        current_chunk_id,
-        // moderation
-        blocked,
-    };
+#[heartbeat]
+fn heartbeat() {
+    jobs::certification::hash_legacy_files_if_due();
+    jobs::uploads::sweep_if_due();
+    jobs::deletion::run_deletion_jobs();
+    jobs::ratelimit::evict_if_due();
+    jobs::warnings::prune_if_due();
+    jobs::trash::purge_if_due();
+}
 
-    storage::stable_save((stable_state,)).expect("Saving to stable store must succeed.");
+// Builds the interface from every `#[candid_method]`, so it can't drift from the code
+candid::export_service!();
+
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn export_candid() -> String {
+    __export_service()
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    let (PostStableState {
-        users,
-        current_file_id,
-        current_chunk_id,
-        // moderation
-        blocked,
-    },) = storage::stable_restore().expect("Failed to read network from stable memory.");
+#[cfg(test)]
+mod tests {
+    use candid::utils::{service_compatible, CandidSource};
+    use std::path::Path;
+
+    use super::__export_service;
+
+    #[test]
+    fn checked_in_candid_interface_matches_the_code() {
+        let generated = __export_service();
+        let checked_in = Path::new(env!("CARGO_MANIFEST_DIR")).join("asset_canister.did");
 
-    USER_STORE.with(|state0| *state0.borrow_mut() = users);
-    CURRENT_FILE_ID.with(|state0| *state0.borrow_mut() = current_file_id);
-    CURRENT_CHUNK_ID.with(|state0| *state0.borrow_mut() = current_chunk_id);
-    // moderation
-    BLOCKED_STORE.with(|state0| *state0.borrow_mut() = blocked);
+        // Checked both ways, since either side may have endpoints the other lacks
+        service_compatible(
+            CandidSource::Text(&generated),
+            CandidSource::File(&checked_in),
+        )
+        .expect("asset_canister.did is out of date, replace it with the generated interface");
+        service_compatible(
+            CandidSource::File(&checked_in),
+            CandidSource::Text(&generated),
+        )
+        .expect("asset_canister.did is out of date, replace it with the generated interface");
+    }
 }
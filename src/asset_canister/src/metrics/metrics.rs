@@ -11,6 +11,7 @@ pub fn collect_metrics() -> CanisterInfo {
     }
 }
 
+#[cfg(target_arch = "wasm32")]
 const WASM_PAGE_SIZE: u64 = 65536;
 
 pub fn get_cycles() -> u64 {
@@ -0,0 +1,59 @@
+use candid::{CandidType, Deserialize};
+
+use crate::auth::{
+    file::{CHUNK_SIZE, MAX_CHUNKS, MAX_FILE_SIZE},
+    ratelimit::{
+        CALL_RATE_LIMIT_WINDOW, FILES_PER_DAY, RATE_LIMIT, WARNINGS_BEFORE_BLOCK, WARNING_DECAY,
+    },
+};
+use crate::database::{file::TRASH_RETENTION, uploads::UPLOAD_TTL, versions::VERSIONS_KEPT};
+
+/// Limits that can be tuned per deployment without upgrading the canister
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Config {
+    // How many calls a principal can make in each call window
+    pub calls_per_window: u64,
+    // In nano seconds
+    pub call_window: u64,
+    pub files_per_day: u64,
+    pub max_chunks: u64,
+    // In bytes
+    pub max_file_size: u64,
+    // The size in bytes of every chunk of a file except the last, at most 1.9MB
+    pub chunk_size: u64,
+    // Uploads that haven't received a chunk for this many nano seconds are swept
+    pub upload_ttl: u64,
+    // Principals are blocked once they pass this many rate limit warnings
+    pub warnings_before_block: u64,
+    // How long a warning counts for in nano seconds
+    pub warning_decay: u64,
+    // How long deleted files stay in the trash in nano seconds
+    pub trash_retention: u64,
+    // How many earlier versions of each file are kept
+    pub versions_kept: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            calls_per_window: CALL_RATE_LIMIT_WINDOW,
+            call_window: RATE_LIMIT,
+            files_per_day: FILES_PER_DAY,
+            max_chunks: MAX_CHUNKS,
+            max_file_size: MAX_FILE_SIZE,
+            chunk_size: CHUNK_SIZE,
+            upload_ttl: UPLOAD_TTL,
+            warnings_before_block: WARNINGS_BEFORE_BLOCK,
+            warning_decay: WARNING_DECAY,
+            trash_retention: TRASH_RETENTION,
+            versions_kept: VERSIONS_KEPT,
+        }
+    }
+}
+
+impl Config {
+    /// The most chunks a new file can have, which both `max_chunks` and `max_file_size` limit
+    pub fn max_chunks_per_file(&self) -> u64 {
+        self.max_chunks.min(self.max_file_size / self.chunk_size)
+    }
+}
@@ -1,14 +1,25 @@
 use candid::Principal;
 use ic_cdk::export::candid::{CandidType, Deserialize};
 use serde_bytes::ByteBuf;
+use sha2::Sha256;
 use sha3::{Digest, Sha3_256};
 use std::collections::HashSet;
 
 use crate::{
-    api::file::FEFile,
-    database::{chunks::ChunkID, file::FileID},
+    api::{
+        error::AssetError,
+        file::{FEFile, FEFileVersion},
+    },
+    auth::file::CHUNK_SIZE,
+    database::{
+        chunks::ChunkID,
+        file::{reserved_bytes, FileID},
+    },
+    models::metadata::FileMetadata,
 };
 
+// The variant names are part of the Candid interface
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Copy)]
 pub enum FileType {
     PNG,
@@ -31,7 +42,7 @@ impl FileType {
         }
     }
 
-    pub fn convert_to_file_type(file_type: &str) -> Result<FileType, String> {
+    pub fn convert_to_file_type(file_type: &str) -> Result<FileType, AssetError> {
         match file_type {
             "image/png" => Ok(FileType::PNG),
             "image/jpeg" => Ok(FileType::JPEG),
@@ -39,7 +50,7 @@ impl FileType {
             "video/mp4" => Ok(FileType::MP4),
             "video/quicktime" => Ok(FileType::MOV),
             "image/webp" => Ok(FileType::WEBP),
-            _ => Err(String::from("Unsupported file type")),
+            _ => Err(AssetError::InvalidFileType),
         }
     }
 
@@ -55,6 +66,22 @@ impl FileType {
     }
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Copy)]
+pub enum UploadStatus {
+    Pending,
+    Committed,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Copy)]
+pub enum Visibility {
+    // Anyone can fetch the file
+    Public,
+    // Anyone with the link can fetch the file, but it is left out of public listings
+    Unlisted,
+    // Only the owner and principals granted access can fetch the file, or anyone with a share link over HTTP
+    Private,
+}
+
 pub type Hash = [u8; 32];
 
 pub fn hash_bytes(value: impl AsRef<[u8]>) -> Hash {
@@ -63,6 +90,13 @@ pub fn hash_bytes(value: impl AsRef<[u8]>) -> Hash {
     hasher.finalize().into()
 }
 
+/// SHA-256 is what HTTP gateways use to verify certified responses
+pub fn sha256_bytes(value: impl AsRef<[u8]>) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(value.as_ref());
+    hasher.finalize().into()
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct File {
     pub id: FileID,
@@ -72,12 +106,49 @@ pub struct File {
     pub file_name: String,
     pub file_type: FileType,
     pub owner: Principal,
+    // Always empty, replaced by `file_metadata`
     pub metadata: String,
     pub deleted_at: Option<u64>,
     pub created_at: u64,
     pub updated_at: u64,
     pub accessors: HashSet<Principal>,
     pub hash: Hash,
+    // SHA-256 of the full file content, certified for HTTP responses
+    // None for files saved before responses were certified
+    pub sha256: Option<Hash>,
+    // None for files saved before uploads had to be committed, which count as committed
+    pub upload_status: Option<UploadStatus>,
+    // None for files saved before visibility could be set, which are public
+    pub visibility: Option<Visibility>,
+    // Share links are signed with this, changing it revokes every link to the file
+    pub share_salt: Option<u64>,
+    // The chunk size when the upload began, None for files uploaded before it was configurable
+    pub chunk_size: Option<u64>,
+    // The order ID of each chunk in `chunk_ids`, since chunks can be shared between files
+    // None for files uploaded before chunks were shared, whose chunks carry their own order ID
+    pub chunk_order_ids: Option<Vec<u64>>,
+    // The version of the current content, None for files whose content has never been replaced
+    pub version: Option<u64>,
+    // Set on the upload of new content for an existing file, which takes its place once committed
+    pub replaces: Option<FileID>,
+    // None for files whose metadata has never been set
+    pub file_metadata: Option<FileMetadata>,
+}
+
+/// Content a file had before it was replaced
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileVersion {
+    pub version: u64,
+    pub file_type: FileType,
+    pub chunk_ids: Vec<ChunkID>,
+    pub chunk_order_ids: Vec<u64>,
+    pub number_of_chunks: u64,
+    pub chunk_size: u64,
+    // Total size of the content in bytes
+    pub size: u64,
+    pub hash: Hash,
+    pub sha256: Option<Hash>,
+    pub replaced_at: u64,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -86,15 +157,86 @@ pub struct FileChunk {
     pub file_id: FileID,
     pub order_id: u64,
     pub chunk_data: ByteBuf,
+    // Always empty, metadata is kept on the file
     pub metadata: String,
     pub deleted_at: Option<u64>,
     pub created_at: u64,
     pub updated_at: u64,
+    // SHA3-256 of the chunk data, identical chunks are stored once
     pub hash: Hash,
-    // todo: add version here
+    // SHA-256 of the chunk data, used in streaming tokens
+    pub sha256: Option<Hash>,
+    // Chunks can be shared between versions, so each version keeps its own list of chunk IDs
 }
 
 impl File {
+    pub fn is_committed(&self) -> bool {
+        self.upload_status != Some(UploadStatus::Pending)
+    }
+
+    /// Whether the file is in the trash, waiting to be restored or purged
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    pub fn get_visibility(&self) -> Visibility {
+        self.visibility.unwrap_or(Visibility::Public)
+    }
+
+    pub fn get_chunk_size(&self) -> u64 {
+        self.chunk_size.unwrap_or(CHUNK_SIZE)
+    }
+
+    /// The bytes charged to the owner while the file is uploading
+    pub fn reserved_bytes(&self) -> u64 {
+        reserved_bytes(self.number_of_chunks, self.get_chunk_size())
+    }
+
+    pub fn get_version(&self) -> u64 {
+        self.version.unwrap_or(1)
+    }
+
+    /// The file as it was at an earlier version, so it can be served like the current one
+    pub fn at_version(&self, version: &FileVersion) -> File {
+        File {
+            file_type: version.file_type,
+            chunk_ids: version.chunk_ids.clone(),
+            chunk_order_ids: Some(version.chunk_order_ids.clone()),
+            number_of_chunks: version.number_of_chunks,
+            chunk_size: Some(version.chunk_size),
+            hash: version.hash,
+            sha256: version.sha256,
+            version: Some(version.version),
+            ..self.clone()
+        }
+    }
+
+    pub fn get_share_salt(&self) -> u64 {
+        self.share_salt.unwrap_or(0)
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.get_visibility() == Visibility::Private
+    }
+
+    /// Whether the principal can fetch the file, the owner is always an accessor
+    pub fn can_be_read_by(&self, principal: Principal) -> bool {
+        !self.is_private() || principal == self.owner || self.accessors.contains(&principal)
+    }
+
+    /// The current version, which has no `replaced_at`
+    pub fn create_fe_version(&self, size: u64) -> FEFileVersion {
+        FEFileVersion {
+            version: self.get_version(),
+            file_type: String::from(self.file_type.as_str()),
+            chunk_ids: self.chunk_ids.clone(),
+            number_of_chunks: self.number_of_chunks,
+            size,
+            url: version_url(&self.url, self.get_version()),
+            replaced_at: None,
+        }
+    }
+
     pub fn create_fe_type(&self) -> FEFile {
         FEFile {
             id: self.id,
@@ -103,10 +245,124 @@ impl File {
             file_name: self.file_name.clone(),
             file_type: String::from(self.file_type.as_str()),
             owner: self.owner,
-            metadata: String::from(self.metadata.as_str()),
+            metadata: self.file_metadata.clone().unwrap_or_default(),
             url: String::from(self.url.as_str()),
             created_at: self.created_at,
             updated_at: self.updated_at,
+            visibility: self.get_visibility(),
+            deleted_at: self.deleted_at,
+            version: self.get_version(),
+            replaces: self.replaces,
+        }
+    }
+}
+
+impl FileVersion {
+    /// Takes the file's URL, which stays the same across versions
+    pub fn create_fe_type(&self, url: &str) -> FEFileVersion {
+        FEFileVersion {
+            version: self.version,
+            file_type: String::from(self.file_type.as_str()),
+            chunk_ids: self.chunk_ids.clone(),
+            number_of_chunks: self.number_of_chunks,
+            size: self.size,
+            url: version_url(url, self.version),
+            replaced_at: Some(self.replaced_at),
+        }
+    }
+}
+
+fn version_url(url: &str, version: u64) -> String {
+    format!("{}?v={}", url, version)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A committed public PNG owned by `owner`, for tests to adjust
+    pub(crate) fn test_file(id: FileID, owner: Principal) -> File {
+        File {
+            id,
+            url: format!("/image/{}", id),
+            chunk_ids: vec![id],
+            number_of_chunks: 1,
+            file_name: String::from("image.png"),
+            file_type: FileType::PNG,
+            owner,
+            metadata: String::new(),
+            deleted_at: None,
+            created_at: 0,
+            updated_at: 0,
+            accessors: HashSet::new(),
+            hash: [0; 32],
+            sha256: None,
+            upload_status: Some(UploadStatus::Committed),
+            visibility: None,
+            share_salt: None,
+            chunk_size: None,
+            chunk_order_ids: None,
+            version: None,
+            replaces: None,
+            file_metadata: None,
         }
     }
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn public_and_unlisted_files_can_be_read_by_anyone() {
+        let mut file = test_file(1, principal(1));
+        assert!(file.can_be_read_by(principal(2)));
+        assert!(file.can_be_read_by(Principal::anonymous()));
+
+        file.visibility = Some(Visibility::Unlisted);
+        assert!(file.can_be_read_by(principal(2)));
+    }
+
+    #[test]
+    fn private_files_can_only_be_read_by_the_owner_and_accessors() {
+        let mut file = test_file(1, principal(1));
+        file.visibility = Some(Visibility::Private);
+        file.accessors.insert(principal(2));
+
+        assert!(file.can_be_read_by(principal(1)));
+        assert!(file.can_be_read_by(principal(2)));
+        assert!(!file.can_be_read_by(principal(3)));
+        assert!(!file.can_be_read_by(Principal::anonymous()));
+    }
+
+    #[test]
+    fn earlier_version_keeps_the_files_identity() {
+        let file = File {
+            version: Some(3),
+            file_metadata: Some(FileMetadata::default()),
+            ..test_file(1, principal(1))
+        };
+        let version = FileVersion {
+            version: 2,
+            file_type: FileType::GIF,
+            chunk_ids: vec![7, 8],
+            chunk_order_ids: vec![1, 0],
+            number_of_chunks: 2,
+            chunk_size: 100,
+            size: 150,
+            hash: [1; 32],
+            sha256: Some([2; 32]),
+            replaced_at: 10,
+        };
+
+        let earlier = file.at_version(&version);
+        assert_eq!(earlier.get_version(), 2);
+        assert_eq!(earlier.file_type, FileType::GIF);
+        assert_eq!(earlier.chunk_ids, vec![7, 8]);
+        assert_eq!(earlier.chunk_order_ids, Some(vec![1, 0]));
+        assert_eq!(earlier.get_chunk_size(), 100);
+        assert_eq!(earlier.sha256, Some([2; 32]));
+        assert_eq!((earlier.id, earlier.owner), (file.id, file.owner));
+        assert_eq!(earlier.file_metadata, file.file_metadata);
+        assert_eq!(version.create_fe_type(&file.url).url, "/image/1?v=2");
+    }
 }
@@ -0,0 +1,115 @@
+use candid::{CandidType, Deserialize};
+
+/// What an owner says about a file, every field is optional
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq)]
+pub struct FileMetadata {
+    pub title: Option<String>,
+    // Describes an image for people who can't see it
+    pub alt_text: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    // Anything else the frontend wants to keep with the file
+    pub custom: Vec<(String, String)>,
+}
+
+impl FileMetadata {
+    /// Trims every field, leaving out empty ones, and makes tags lowercase and unique
+    /// Later custom values replace earlier ones with the same key
+    pub fn normalise(self) -> FileMetadata {
+        let trimmed = |value: Option<String>| {
+            value
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+
+        let mut tags: Vec<String> = vec![];
+        self.tags
+            .iter()
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .for_each(|tag| {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            });
+
+        let mut custom: Vec<(String, String)> = vec![];
+        self.custom
+            .into_iter()
+            .map(|(key, value)| (key.trim().to_string(), value))
+            .filter(|(key, _)| !key.is_empty())
+            .for_each(|(key, value)| {
+                custom.retain(|(existing_key, _)| *existing_key != key);
+                custom.push((key, value));
+            });
+
+        FileMetadata {
+            title: trimmed(self.title),
+            alt_text: trimmed(self.alt_text),
+            description: trimmed(self.description),
+            tags,
+            custom,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_trims_and_drops_empty_fields() {
+        let metadata = FileMetadata {
+            title: Some(String::from("  Sunset ")),
+            alt_text: Some(String::from("   ")),
+            description: None,
+            ..Default::default()
+        }
+        .normalise();
+
+        assert_eq!(metadata.title, Some(String::from("Sunset")));
+        assert_eq!(metadata.alt_text, None);
+        assert_eq!(metadata.description, None);
+    }
+
+    #[test]
+    fn normalise_makes_tags_lowercase_and_unique() {
+        let tags = ["Beach", " beach", "", "SUNSET ", "beach"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let metadata = FileMetadata {
+            tags,
+            ..Default::default()
+        }
+        .normalise();
+
+        assert_eq!(metadata.tags, vec!["beach", "sunset"]);
+    }
+
+    #[test]
+    fn normalise_keeps_the_last_custom_value_for_a_key() {
+        let custom = [
+            ("camera", "A"),
+            (" lens ", "50mm"),
+            (" ", "none"),
+            ("camera ", "B"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect();
+        let metadata = FileMetadata {
+            custom,
+            ..Default::default()
+        }
+        .normalise();
+
+        assert_eq!(
+            metadata.custom,
+            vec![
+                (String::from("lens"), String::from("50mm")),
+                (String::from("camera"), String::from("B")),
+            ]
+        );
+    }
+}
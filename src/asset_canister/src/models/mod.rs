@@ -1 +1,4 @@
+pub mod config;
 pub mod file;
+pub mod metadata;
+pub mod role;
@@ -0,0 +1,11 @@
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+/// Roles from most to least powerful, what each can do is set in `auth::roles`
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord, Copy)]
+pub enum Role {
+    Owner,
+    Admin,
+    Moderator,
+    Uploader,
+    Reader,
+}